@@ -0,0 +1,61 @@
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::shared_data::{load_json_state, save_json_state};
+
+const STATE_FILE: &str = "backfill_state.json";
+
+/// Limits of single backfill run.
+#[derive(Debug, Clone, Default)]
+pub struct BackfillOptions {
+    /// Only statuses posted on this day or later are described.
+    pub since: Option<NaiveDate>,
+    /// Only statuses posted on this day or earlier are described.
+    pub until: Option<NaiveDate>,
+    /// Maximum number of descriptions generated per day.
+    pub daily_budget: Option<usize>,
+    /// Ignore saved cursor and start from the newest status.
+    pub restart: bool,
+}
+
+/// Progress of backfill persisted between runs, so it can resume after crash or when budget is used up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackfillState {
+    /// ID of the last status which was fully handled, next page starts below it.
+    pub cursor: Option<String>,
+    pub finished: bool,
+    pub budget_day: NaiveDate,
+    pub described_today: usize,
+}
+
+impl Default for BackfillState {
+    fn default() -> Self {
+        Self {
+            cursor: None,
+            finished: false,
+            budget_day: Local::now().date_naive(),
+            described_today: 0,
+        }
+    }
+}
+
+impl BackfillState {
+    pub fn load() -> Result<Self> {
+        load_json_state(STATE_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json_state(STATE_FILE, self)
+    }
+
+    /// Returns how many descriptions can still be generated today, resetting counter on new day.
+    pub fn remaining_budget(&mut self, daily_budget: Option<usize>) -> Option<usize> {
+        let today = Local::now().date_naive();
+        if self.budget_day != today {
+            self.budget_day = today;
+            self.described_today = 0;
+        }
+        daily_budget.map(|budget| budget.saturating_sub(self.described_today))
+    }
+}
@@ -0,0 +1,424 @@
+use std::{sync::Arc, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use image::imageops::FilterType;
+use log::{debug, error};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::CacheConfig;
+use crate::error::{Error, Result};
+use crate::shared_data::{load_json_state, load_json_state_or_default, save_json_state};
+use crate::vision::Description;
+
+const CACHE_FILE: &str = "description_cache.json";
+
+/// Identity of image content, independent of URL it was posted under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageKey {
+    pub sha256: String,
+    /// Difference hash of downscaled image, survives re-encoding and resizing.
+    pub perceptual: Option<u64>,
+}
+
+impl ImageKey {
+    pub fn of(bytes: &[u8], perceptual: bool) -> Self {
+        let sha256 = Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let perceptual = if perceptual {
+            difference_hash(bytes)
+        } else {
+            None
+        };
+        Self { sha256, perceptual }
+    }
+}
+
+// 64 bits telling whether each pixel of 9x8 grayscale thumbnail is brighter than its right neighbour
+fn difference_hash(bytes: &[u8]) -> Option<u64> {
+    let image = match image::load_from_memory(bytes) {
+        Ok(image) => image,
+        Err(err) => {
+            debug!("Cannot decode image for perceptual hash: {}", err);
+            return None;
+        }
+    };
+    let thumbnail = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    Some(hash)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    sha256: String,
+    perceptual: Option<u64>,
+    language: String,
+    /// Alt text.
+    description: String,
+    /// Remaining parts of structured description, if it was requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    details: Option<Description>,
+    created: DateTime<Utc>,
+}
+
+impl CacheEntry {
+    fn to_description(&self) -> Description {
+        match &self.details {
+            Some(details) => Description {
+                alt_text: self.description.clone(),
+                ..details.clone()
+            },
+            None => Description::from_alt_text(self.description.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub near_hits: u64,
+    pub misses: u64,
+}
+
+/// Descriptions of already described images, so re-posted images are not sent to the provider again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DescriptionCache {
+    entries: Vec<CacheEntry>,
+    #[serde(default)]
+    stats: CacheStats,
+}
+
+impl DescriptionCache {
+    pub fn new() -> Self {
+        load_json_state_or_default(CACHE_FILE)
+    }
+
+    pub fn load() -> Result<Self> {
+        load_json_state(CACHE_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json_state(CACHE_FILE, self)
+    }
+
+    /// Returns description of identical image, or of similar one if perceptual matching is enabled.
+    pub fn get(
+        &mut self,
+        key: &ImageKey,
+        language: &str,
+        config: &CacheConfig,
+    ) -> Option<Description> {
+        self.remove_expired(config);
+        let same_language = || {
+            self.entries
+                .iter()
+                .filter(|entry| entry.language == language)
+        };
+        if let Some(entry) = same_language().find(|entry| entry.sha256 == key.sha256) {
+            let description = entry.to_description();
+            self.stats.hits += 1;
+            return Some(description);
+        }
+        let near = key
+            .perceptual
+            .filter(|_| config.perceptual)
+            .and_then(|hash| {
+                same_language()
+                    .filter_map(|entry| {
+                        let distance = (entry.perceptual? ^ hash).count_ones();
+                        (distance <= config.max_distance).then_some((distance, entry))
+                    })
+                    .min_by_key(|(distance, _)| *distance)
+                    .map(|(_, entry)| entry.to_description())
+            });
+        match near {
+            Some(description) => {
+                self.stats.near_hits += 1;
+                Some(description)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &ImageKey, language: &str, description: Description) {
+        self.entries
+            .retain(|entry| !(entry.sha256 == key.sha256 && entry.language == language));
+        self.entries.push(CacheEntry {
+            sha256: key.sha256.clone(),
+            perceptual: key.perceptual,
+            language: language.to_string(),
+            details: Some(Description {
+                alt_text: String::new(),
+                ..description.clone()
+            })
+            .filter(|details| *details != Description::default()),
+            description: description.alt_text,
+            created: Utc::now(),
+        });
+    }
+
+    fn remove_expired(&mut self, config: &CacheConfig) {
+        let oldest = Utc::now() - chrono::Duration::days(config.ttl_days as i64);
+        self.entries.retain(|entry| entry.created >= oldest);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+pub static DESCRIPTION_CACHE: Lazy<Mutex<DescriptionCache>> =
+    Lazy::new(|| Mutex::new(DescriptionCache::new()));
+
+/// Looks up description of image, returns key to store new description under.
+pub async fn lookup(
+    bytes: Arc<[u8]>,
+    language: &str,
+    config: &CacheConfig,
+) -> Result<(ImageKey, Option<Description>)> {
+    let perceptual = config.perceptual;
+    let key = tokio::task::spawn_blocking(move || ImageKey::of(&bytes, perceptual))
+        .await
+        .map_err(|err| Error::Io(std::io::Error::other(err)))?;
+    // hit and miss counts are saved with the next stored description or on exit
+    let description = DESCRIPTION_CACHE
+        .lock()
+        .unwrap()
+        .get(&key, language, config);
+    Ok((key, description))
+}
+
+pub fn store(key: &ImageKey, language: &str, description: Description) {
+    let mut cache = DESCRIPTION_CACHE.lock().unwrap();
+    cache.insert(key, language, description);
+    if let Err(err) = cache.save() {
+        error!("{}", err);
+    }
+}
+
+/// Saves cache with statistics collected since the last stored description.
+pub fn flush() {
+    if let Err(err) = DESCRIPTION_CACHE.lock().unwrap().save() {
+        error!("{}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    use image::{DynamicImage, GrayImage, ImageOutputFormat, Luma};
+
+    fn key(sha256: &str, perceptual: Option<u64>) -> ImageKey {
+        ImageKey {
+            sha256: sha256.to_string(),
+            perceptual,
+        }
+    }
+
+    fn perceptual_config(max_distance: u32) -> CacheConfig {
+        CacheConfig {
+            perceptual: true,
+            max_distance,
+            ..CacheConfig::default()
+        }
+    }
+
+    fn png(width: u32, height: u32, pixel: impl Fn(u32, u32) -> u8) -> Vec<u8> {
+        let image = GrayImage::from_fn(width, height, |x, y| Luma([pixel(x, y)]));
+        let mut bytes = Vec::new();
+        DynamicImage::ImageLuma8(image)
+            .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn description_is_found_only_in_same_language() {
+        let mut cache = DescriptionCache::default();
+        let config = CacheConfig::default();
+        cache.insert(
+            &key("abc", None),
+            "en",
+            Description::from_alt_text("A cat".to_string()),
+        );
+        assert_eq!(
+            cache.get(&key("abc", None), "en", &config),
+            Some(Description::from_alt_text("A cat".to_string()))
+        );
+        assert_eq!(cache.get(&key("abc", None), "pl", &config), None);
+        assert_eq!(cache.get(&key("def", None), "en", &config), None);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.near_hits, stats.misses), (1, 0, 2));
+    }
+
+    #[test]
+    fn inserting_again_replaces_description() {
+        let mut cache = DescriptionCache::default();
+        cache.insert(
+            &key("abc", None),
+            "en",
+            Description::from_alt_text("A cat".to_string()),
+        );
+        cache.insert(
+            &key("abc", None),
+            "en",
+            Description::from_alt_text("A dog".to_string()),
+        );
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            cache
+                .get(&key("abc", None), "en", &CacheConfig::default())
+                .map(|description| description.alt_text),
+            Some("A dog".to_string())
+        );
+    }
+
+    #[test]
+    fn expired_description_is_removed() {
+        let mut cache = DescriptionCache::default();
+        let config = CacheConfig::default();
+        cache.insert(
+            &key("abc", None),
+            "en",
+            Description::from_alt_text("A cat".to_string()),
+        );
+        cache.entries[0].created = Utc::now() - chrono::Duration::days(config.ttl_days as i64 + 1);
+        assert_eq!(cache.get(&key("abc", None), "en", &config), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn description_within_ttl_is_kept() {
+        let mut cache = DescriptionCache::default();
+        let config = CacheConfig::default();
+        cache.insert(
+            &key("abc", None),
+            "en",
+            Description::from_alt_text("A cat".to_string()),
+        );
+        cache.entries[0].created = Utc::now() - chrono::Duration::days(config.ttl_days as i64 - 1);
+        assert!(cache.get(&key("abc", None), "en", &config).is_some());
+    }
+
+    #[test]
+    fn similar_image_matches_within_distance() {
+        let mut cache = DescriptionCache::default();
+        cache.insert(
+            &key("abc", Some(0b1111)),
+            "en",
+            Description::from_alt_text("A cat".to_string()),
+        );
+        let copy = key("def", Some(0));
+        assert!(cache.get(&copy, "en", &perceptual_config(4)).is_some());
+        assert_eq!(cache.get(&copy, "en", &perceptual_config(3)), None);
+        // perceptual matching disabled
+        assert_eq!(cache.get(&copy, "en", &CacheConfig::default()), None);
+        assert_eq!(cache.stats().near_hits, 1);
+    }
+
+    #[test]
+    fn closest_similar_image_is_chosen() {
+        let mut cache = DescriptionCache::default();
+        cache.insert(
+            &key("far", Some(0b111)),
+            "en",
+            Description::from_alt_text("Far".to_string()),
+        );
+        cache.insert(
+            &key("near", Some(0b1)),
+            "en",
+            Description::from_alt_text("Near".to_string()),
+        );
+        assert_eq!(
+            cache
+                .get(&key("new", Some(0)), "en", &perceptual_config(4))
+                .map(|description| description.alt_text),
+            Some("Near".to_string())
+        );
+    }
+
+    #[test]
+    fn details_of_structured_description_are_kept() {
+        let mut cache = DescriptionCache::default();
+        let description = Description {
+            alt_text: "A cat".to_string(),
+            long_description: "A black cat sleeping on a red sofa".to_string(),
+            detected_text: String::new(),
+            hashtags: vec!["cat".to_string()],
+            content_warnings: vec!["eye contact".to_string()],
+        };
+        cache.insert(&key("abc", None), "en", description.clone());
+        assert_eq!(
+            cache.get(&key("abc", None), "en", &CacheConfig::default()),
+            Some(description)
+        );
+    }
+
+    #[test]
+    fn plain_description_has_no_details() {
+        let mut cache = DescriptionCache::default();
+        cache.insert(
+            &key("abc", None),
+            "en",
+            Description::from_alt_text("A cat".to_string()),
+        );
+        assert_eq!(cache.entries[0].details, None);
+    }
+
+    #[test]
+    fn difference_hash_compares_neighbouring_pixels() {
+        let darker_to_right = png(90, 80, |x, _| 255 - (x * 2) as u8);
+        assert_eq!(difference_hash(&darker_to_right), Some(u64::MAX));
+        let brighter_to_right = png(90, 80, |x, _| (x * 2) as u8);
+        assert_eq!(difference_hash(&brighter_to_right), Some(0));
+    }
+
+    #[test]
+    fn difference_hash_survives_resizing() {
+        // stripes of 9 columns and 8 rows, alternating like a checkerboard
+        let pattern = |x: u32, y: u32, size: u32| [200, 50][((x * 9 / size + y) % 2) as usize];
+        let small = png(90, 80, |x, y| pattern(x, y * 8 / 80, 90));
+        let large = png(180, 160, |x, y| pattern(x, y * 8 / 160, 180));
+        assert_eq!(difference_hash(&small), difference_hash(&large));
+    }
+
+    #[test]
+    fn difference_hash_of_invalid_image_is_none() {
+        assert_eq!(difference_hash(b"not an image"), None);
+    }
+
+    #[test]
+    fn image_key_is_sha256_of_content() {
+        let key = ImageKey::of(b"abc", false);
+        assert_eq!(
+            key.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(key.perceptual, None);
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::NaiveDate;
+use clap::{builder::PossibleValue, Arg, ArgAction, ArgMatches, Command};
+use log::LevelFilter;
+
+use crate::backfill::BackfillOptions;
+use crate::config::{Config, ConfigError, DEFAULT_CONFIG_PATH};
+use crate::report::DEFAULT_REPORT_PATH;
+
+pub fn command() -> Command {
+    Command::new("MastoVision")
+        .version("0.1.0")
+        .author("pecet")
+        .about("Generates image descriptions for Mastodon")
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .global(true)
+                .help("Path to configuration file")
+                .default_value(DEFAULT_CONFIG_PATH),
+        )
+        .arg(
+            Arg::new("verbosity level")
+                .short('v')
+                .long("verbosity")
+                .global(true)
+                .value_parser([
+                    PossibleValue::new("info"),
+                    PossibleValue::new("debug"),
+                    PossibleValue::new("trace"),
+                    PossibleValue::new("warn"),
+                    PossibleValue::new("error"),
+                    PossibleValue::new("quiet"),
+                ])
+                .default_value("info"),
+        )
+        .arg(
+            Arg::new("dry run")
+                .long("dry-run")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Generate descriptions but write them to report instead of editing statuses"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .global(true)
+                .default_value(DEFAULT_REPORT_PATH)
+                .help("Dry-run report file, Markdown for .md extension, JSON Lines otherwise"),
+        )
+        .subcommand(
+            Command::new("run").about("Runs streaming and manual refresh loops (default)"),
+        )
+        .subcommand(
+            Command::new("describe")
+                .about("Prints description of image file or URL without touching Mastodon")
+                .arg(
+                    Arg::new("source")
+                        .required(true)
+                        .help("Path to image file or image URL"),
+                )
+                .arg(
+                    Arg::new("language")
+                        .short('l')
+                        .long("language")
+                        .help("Language code of description, mastodon.default_language if not given"),
+                )
+                .arg(
+                    Arg::new("context")
+                        .long("context")
+                        .default_value("")
+                        .help("Text of message the image belongs to"),
+                ),
+        )
+        .subcommand(
+            Command::new("process")
+                .about("Describes images of single status right now")
+                .arg(
+                    Arg::new("status")
+                        .required(true)
+                        .help("Status ID or URL"),
+                ),
+        )
+        .subcommand(
+            Command::new("backfill")
+                .about("Describes images in whole history of statuses, resuming previous run")
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_parser(parse_date)
+                        .help("Only statuses posted on this day or later (YYYY-MM-DD)"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .value_parser(parse_date)
+                        .help("Only statuses posted on this day or earlier (YYYY-MM-DD)"),
+                )
+                .arg(
+                    Arg::new("daily budget")
+                        .long("daily-budget")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Maximum number of descriptions per day, overrides backfill.daily_budget"),
+                )
+                .arg(
+                    Arg::new("restart")
+                        .long("restart")
+                        .action(ArgAction::SetTrue)
+                        .help("Ignore saved progress and start from the newest status"),
+                ),
+        )
+        .subcommand(Command::new("status").about("Shows state of processed statuses"))
+        .subcommand(
+            Command::new("check-config")
+                .about("Validates configuration file and credentials, then exits"),
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Registers application on Mastodon instance and writes configuration file")
+                .arg(Arg::new("instance").help("Mastodon instance URL or domain"))
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Overwrite existing configuration file"),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Configuration file utilities")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("schema").about("Prints JSON Schema of configuration file"),
+                ),
+        )
+}
+
+pub fn get_log_level(matches: &ArgMatches) -> LevelFilter {
+    // convert matches to LevelFilter
+    matches
+        .get_one("verbosity level")
+        .cloned()
+        .map_or(LevelFilter::Info, |level: String| match level.as_str() {
+            "info" => LevelFilter::Info,
+            "debug" => LevelFilter::Debug,
+            "trace" => LevelFilter::Trace,
+            "warn" => LevelFilter::Warn,
+            "error" => LevelFilter::Error,
+            "quiet" => LevelFilter::Off,
+            _ => LevelFilter::Info,
+        })
+}
+
+pub fn get_config_path(matches: &ArgMatches) -> String {
+    matches
+        .get_one::<String>("config")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string())
+}
+
+/// Returns path of dry-run report if dry-run mode was requested.
+pub fn get_dry_run_report_path(matches: &ArgMatches) -> Option<String> {
+    if matches.get_flag("dry run") {
+        matches.get_one::<String>("report").cloned()
+    } else {
+        None
+    }
+}
+
+/// Loads and validates configuration, used by every subcommand which needs it.
+pub fn load_config(path: &str) -> Result<Config, ConfigError> {
+    let config = Config::load(path)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Loads configuration for subcommands which don't talk to Mastodon, like `describe`.
+pub fn load_config_without_mastodon(path: &str) -> Result<Config, ConfigError> {
+    let config = Config::load(path)?;
+    config.validate_without_mastodon()?;
+    Ok(config)
+}
+
+pub fn get_backfill_options(matches: &ArgMatches) -> BackfillOptions {
+    BackfillOptions {
+        since: matches.get_one::<NaiveDate>("since").cloned(),
+        until: matches.get_one::<NaiveDate>("until").cloned(),
+        daily_budget: matches.get_one::<usize>("daily budget").cloned(),
+        restart: matches.get_flag("restart"),
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|err| format!("expected date in YYYY-MM-DD format ({})", err))
+}
+
+/// Extracts status ID from plain ID or status URL (e.g. https://example.com/@user/123456).
+pub fn parse_status_id(status: &str) -> Option<String> {
+    let id = status
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+        Some(id.to_string())
+    } else {
+        None
+    }
+}
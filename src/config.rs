@@ -1,27 +1,126 @@
-use mastodon_async::Data;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use mastodon_async::{Data, Mastodon};
+use reqwest::Url;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+pub const DEFAULT_CONFIG_PATH: &str = "config.json";
+
+// Mastodon will not return more than this many statuses per page
+const MAX_STATUSES_PER_REQUEST: usize = 40;
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 struct MastodonConfig {
     base_url: String,
+    #[serde(default)]
     client_id: String,
+    #[serde(default)]
     client_secret: String,
     access_token: String,
+    /// Language of descriptions when status has no language set and it cannot be detected.
+    #[serde(default = "default_language")]
+    default_language: String,
+}
+
+fn default_language() -> String {
+    "en".to_string()
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 struct GptConfig {
     access_token: String,
+    #[serde(default = "default_model")]
     model: String,
+    #[serde(default = "default_max_tokens")]
     max_tokens: usize,
+    /// Ask for JSON with alt text, long description, detected text, hashtags and content warnings.
+    /// Requires model supporting structured outputs, e.g. `gpt-4o`.
+    #[serde(default)]
+    structured_output: bool,
+    /// Describe all images of a status in single request instead of one request per image.
+    #[serde(default)]
+    batch_images: bool,
+    #[serde(flatten)]
+    limits: GptLimits,
+    /// Prices of models by name, added to built-in prices of known models.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    prices: HashMap<String, ModelPrice>,
+}
+
+/// Price of model in USD per million tokens.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, JsonSchema)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl ModelPrice {
+    /// Published prices of models which can be used for image descriptions.
+    fn builtin(model: &str) -> Option<Self> {
+        let (prompt, completion) = match model {
+            "gpt-4-vision-preview" | "gpt-4-turbo" | "gpt-4-turbo-2024-04-09" => (10.0, 30.0),
+            "gpt-4o" | "gpt-4o-2024-05-13" => (5.0, 15.0),
+            "gpt-4o-mini" | "gpt-4o-mini-2024-07-18" => (0.15, 0.6),
+            _ => return None,
+        };
+        Some(Self { prompt, completion })
+    }
+}
+
+/// Limits of requests sent to the provider, shared by streaming and manual loops. Read at
+/// startup only, reload keeps previous limits.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct GptLimits {
+    pub max_concurrent_requests: usize,
+    pub requests_per_minute: Option<usize>,
+    pub tokens_per_minute: Option<usize>,
+}
+
+impl Default for GptLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: 4,
+            requests_per_minute: None,
+            tokens_per_minute: None,
+        }
+    }
+}
+
+pub(crate) fn default_model() -> String {
+    "gpt-4-vision-preview".to_string()
+}
+
+fn default_max_tokens() -> usize {
+    384
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
 pub struct GeneralConfig {
     trigger_word: String,
+    /// Seconds to wait for in-flight work on SIGINT/SIGTERM before giving up on it.
+    pub shutdown_timeout: u64,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            trigger_word: "!ad".to_string(),
+            shutdown_timeout: 30,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
 pub struct ManualRefreshConfig {
     pub enabled: bool,
     pub interval: u64,
@@ -30,29 +129,795 @@ pub struct ManualRefreshConfig {
     pub initial_statuses: usize,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl Default for ManualRefreshConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: 120,
+            statuses: 10,
+            initial_delay: 10,
+            initial_statuses: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
 pub struct StreamingConfig {
     pub enabled: bool,
+    /// Upper limit in seconds of exponential backoff between reconnection attempts.
+    pub max_reconnect_delay: u64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_reconnect_delay: 300,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct BackfillConfig {
+    /// Maximum number of descriptions generated by backfill per day, unlimited if not set.
+    pub daily_budget: Option<usize>,
+}
+
+/// Posts of the thread and quoted post included in the prompt.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct ThreadContextConfig {
+    pub enabled: bool,
+    /// Number of closest preceding posts of the thread included.
+    pub max_ancestors: usize,
+    /// Approximate number of characters of context from other posts.
+    pub max_length: usize,
+}
+
+impl Default for ThreadContextConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_ancestors: 3,
+            max_length: 1000,
+        }
+    }
+}
+
+/// What is done with content warnings suggested by the model.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentWarningAction {
+    /// Direct message with suggestion is posted to own account.
+    Suggest,
+    /// Content warning is set and media marked sensitive in the same edit which adds descriptions.
+    Apply,
+}
+
+/// Detection of images which likely need content warning. Requires `gpt.structured_output`.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct ContentWarningConfig {
+    pub enabled: bool,
+    /// Topics the model looks for, other suggestions are ignored.
+    pub topics: Vec<String>,
+    pub action: ContentWarningAction,
+}
+
+impl Default for ContentWarningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            topics: [
+                "gore",
+                "blood",
+                "injury",
+                "flashing imagery",
+                "eye contact",
+                "food",
+                "spiders",
+                "insects",
+                "snakes",
+                "needles",
+                "nudity",
+                "death",
+            ]
+            .iter()
+            .map(|topic| topic.to_string())
+            .collect(),
+            action: ContentWarningAction::Suggest,
+        }
+    }
+}
+
+/// How text recognized by OCR is used.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OcrMode {
+    /// Recognized text is given to the model as context, so it can quote it accurately.
+    Context,
+    /// Recognized text is appended verbatim to the description.
+    Transcribe,
+}
+
+/// Recognition of text in screenshots and other text-heavy images with local tesseract.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct OcrConfig {
+    pub enabled: bool,
+    /// Path to tesseract executable.
+    pub command: String,
+    /// Tesseract languages, e.g. `eng+pol`.
+    pub languages: String,
+    /// Images with fewer recognized words are treated as not containing text.
+    pub min_words: usize,
+    pub mode: OcrMode,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            command: "tesseract".to_string(),
+            languages: "eng".to_string(),
+            min_words: 15,
+            mode: OcrMode::Context,
+        }
+    }
+}
+
+/// Descriptions in several languages combined into one alt text, for bilingual accounts.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct MultilingualConfig {
+    pub enabled: bool,
+    /// Languages of descriptions, used when `detect` finds fewer than two languages.
+    pub languages: Vec<String>,
+    /// Pick languages from hashtags and paragraphs of the status.
+    pub detect: bool,
+    /// Hashtags (without `#`) marking language of the status, e.g. `polski = "pl"`.
+    pub hashtags: HashMap<String, String>,
+    /// Placed between descriptions in different languages.
+    pub separator: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl MultilingualConfig {
+    /// Language marked by hashtag, hashtags are compared case-insensitively like in Mastodon.
+    pub fn language_of_hashtag(&self, hashtag: &str) -> Option<&str> {
+        let hashtag = hashtag.trim_start_matches('#').to_lowercase();
+        self.hashtags
+            .iter()
+            .find(|(name, _)| name.trim_start_matches('#').to_lowercase() == hashtag)
+            .map(|(_, language)| language.as_str())
+    }
+}
+
+impl Default for MultilingualConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            languages: Vec::new(),
+            detect: false,
+            hashtags: HashMap::new(),
+            separator: " / ".to_string(),
+        }
+    }
+}
+
+/// Checks of descriptions returned by the model before they are published.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct QualityConfig {
+    /// How many times the model is asked again, with more specific prompt, after unusable description.
+    pub retries: usize,
+    /// Model tried when configured one keeps returning unusable descriptions.
+    pub fallback_model: Option<String>,
+    /// Descriptions with fewer characters are rejected.
+    pub min_length: usize,
+    /// Phrases which mean the model refused to describe the image, matched case-insensitively.
+    pub refusal_phrases: Vec<String>,
+    /// Filler at the beginning of description which is removed, e.g. "This image shows".
+    pub filler_prefixes: Vec<String>,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        Self {
+            retries: 1,
+            fallback_model: None,
+            min_length: 15,
+            refusal_phrases: strings(&[
+                "I'm sorry",
+                "I’m sorry",
+                "I am sorry",
+                "I can't help",
+                "I can’t help",
+                "I cannot help",
+                "I'm unable to",
+                "I’m unable to",
+                "I am unable to",
+                "I can't assist",
+                "I can’t assist",
+                "I cannot assist",
+                "I can't provide",
+                "I cannot provide",
+            ]),
+            filler_prefixes: strings(&[
+                "This image shows",
+                "The image shows",
+                "This image depicts",
+                "The image depicts",
+                "This picture shows",
+                "The picture shows",
+                "This is an image of",
+                "This is a picture of",
+                "This is a photo of",
+                "In this image",
+                "In the image",
+                "The image features",
+                "This image features",
+            ]),
+        }
+    }
+}
+
+/// Reuse of descriptions for images which were already described.
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    /// Days after which cached description is generated again.
+    pub ttl_days: u64,
+    /// Also match re-encoded or resized copies of image by perceptual hash.
+    pub perceptual: bool,
+    /// Maximum number of differing bits of perceptual hashes of matching images (0-64).
+    pub max_distance: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_days: 30,
+            perceptual: false,
+            max_distance: 4,
+        }
+    }
+}
+
+/// Spending caps in USD, descriptions are held back until the period rolls over once reached.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct BudgetConfig {
+    pub daily: Option<f64>,
+    pub monthly: Option<f64>,
+    /// Send direct message to own account when cap is reached.
+    pub notify: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct Config {
     mastodon: MastodonConfig,
     gpt: GptConfig,
+    #[serde(default)]
     general: GeneralConfig,
+    #[serde(default)]
     manual_refresh: ManualRefreshConfig,
+    #[serde(default)]
     streaming: StreamingConfig,
+    #[serde(default)]
+    backfill: BackfillConfig,
+    #[serde(default)]
+    budget: BudgetConfig,
+    #[serde(default)]
+    cache: CacheConfig,
+    #[serde(default)]
+    quality: QualityConfig,
+    #[serde(default)]
+    multilingual: MultilingualConfig,
+    #[serde(default)]
+    ocr: OcrConfig,
+    #[serde(default)]
+    content_warnings: ContentWarningConfig,
+    #[serde(default)]
+    thread_context: ThreadContextConfig,
+}
+
+/// Supported configuration file formats, picked by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "json" => Some(ConfigFormat::Json),
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// Single problem found while validating configuration.
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    UnknownFormat(PathBuf),
+    Parse {
+        path: PathBuf,
+        field: String,
+        message: String,
+        location: Option<(usize, usize)>,
+    },
+    Invalid(Vec<ConfigIssue>),
+    Write {
+        path: PathBuf,
+        message: String,
+    },
+    Remote {
+        service: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => write!(
+                f,
+                "Failed to read {}: {}. Please make sure it exists and is readable.",
+                path.display(),
+                source
+            ),
+            ConfigError::UnknownFormat(path) => write!(
+                f,
+                "Unknown format of {}, please use .json, .toml or .yaml extension",
+                path.display()
+            ),
+            ConfigError::Parse {
+                path,
+                field,
+                message,
+                location,
+            } => {
+                write!(f, "Failed to parse {} (field '{}'", path.display(), field)?;
+                if let Some((line, column)) = location {
+                    write!(f, ", line {}, column {}", line, column)?;
+                }
+                write!(f, "): {}", message)
+            }
+            ConfigError::Invalid(issues) => {
+                write!(f, "Invalid configuration:")?;
+                for issue in issues {
+                    write!(f, "\n - {}", issue)?;
+                }
+                Ok(())
+            }
+            ConfigError::Write { path, message } => {
+                write!(f, "Failed to write {}: {}", path.display(), message)
+            }
+            ConfigError::Remote { service, message } => {
+                write!(f, "{} check failed: {}", service, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
 }
 
 impl Config {
-    pub fn from_json() -> Self {
-        let config = std::fs::read_to_string("config.json").unwrap_or_else(|_| {
-            panic!("Failed to read config.json. Please make sure it exists and is readable.")
-        });
-        serde_json::from_str(&config).unwrap_or_else(|_| {
-            panic!("Failed to parse config.json. Please make sure it is valid JSON.")
-        })
+    /// Creates configuration with given credentials and defaults for everything else.
+    pub fn new(
+        base_url: String,
+        client_id: String,
+        client_secret: String,
+        access_token: String,
+        gpt_access_token: String,
+    ) -> Self {
+        Self {
+            mastodon: MastodonConfig {
+                base_url,
+                client_id,
+                client_secret,
+                access_token,
+                default_language: default_language(),
+            },
+            gpt: GptConfig {
+                access_token: gpt_access_token,
+                model: default_model(),
+                max_tokens: default_max_tokens(),
+                structured_output: false,
+                batch_images: false,
+                limits: GptLimits::default(),
+                prices: HashMap::new(),
+            },
+            general: GeneralConfig::default(),
+            manual_refresh: ManualRefreshConfig::default(),
+            streaming: StreamingConfig::default(),
+            backfill: BackfillConfig::default(),
+            budget: BudgetConfig::default(),
+            cache: CacheConfig::default(),
+            quality: QualityConfig::default(),
+            multilingual: MultilingualConfig::default(),
+            ocr: OcrConfig::default(),
+            content_warnings: ContentWarningConfig::default(),
+            thread_context: ThreadContextConfig::default(),
+        }
+    }
+
+    /// Reads and parses configuration file, reporting path of the offending field on failure.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let config = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |field: String, message: String, location| ConfigError::Parse {
+            path: path.to_path_buf(),
+            field,
+            message,
+            location,
+        };
+        match ConfigFormat::from_path(path) {
+            Some(ConfigFormat::Json) => {
+                let deserializer = &mut serde_json::Deserializer::from_str(&config);
+                serde_path_to_error::deserialize(deserializer).map_err(|err| {
+                    let field = err.path().to_string();
+                    let err = err.into_inner();
+                    let location = Some((err.line(), err.column()));
+                    parse_error(field, err.to_string(), location)
+                })
+            }
+            Some(ConfigFormat::Toml) => {
+                let deserializer = toml::Deserializer::new(&config);
+                serde_path_to_error::deserialize(deserializer).map_err(|err| {
+                    let field = err.path().to_string();
+                    let err = err.into_inner();
+                    let location = err.span().map(|span| line_and_column(&config, span.start));
+                    parse_error(field, err.message().to_string(), location)
+                })
+            }
+            Some(ConfigFormat::Yaml) => {
+                let deserializer = serde_yaml::Deserializer::from_str(&config);
+                serde_path_to_error::deserialize(deserializer).map_err(|err| {
+                    let field = err.path().to_string();
+                    let err = err.into_inner();
+                    let location = err
+                        .location()
+                        .map(|location| (location.line(), location.column()));
+                    parse_error(field, err.to_string(), location)
+                })
+            }
+            None => Err(ConfigError::UnknownFormat(path.to_path_buf())),
+        }
+    }
+
+    /// Writes configuration in format matching extension of given path. File is readable only by
+    /// its owner, as it contains credentials.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let path = path.as_ref();
+        let write_error = |message: String| ConfigError::Write {
+            path: path.to_path_buf(),
+            message,
+        };
+        let contents = match ConfigFormat::from_path(path) {
+            Some(ConfigFormat::Json) => {
+                serde_json::to_string_pretty(self).map_err(|err| write_error(err.to_string()))?
+            }
+            Some(ConfigFormat::Toml) => {
+                toml::to_string_pretty(self).map_err(|err| write_error(err.to_string()))?
+            }
+            Some(ConfigFormat::Yaml) => {
+                serde_yaml::to_string(self).map_err(|err| write_error(err.to_string()))?
+            }
+            None => return Err(ConfigError::UnknownFormat(path.to_path_buf())),
+        };
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|err| write_error(err.to_string()))?;
+        // mode is applied only to new files, overwritten ones keep their permissions
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(|err| write_error(err.to_string()))?;
+        file.write_all(contents.as_bytes())
+            .map_err(|err| write_error(err.to_string()))
     }
+
+    /// JSON Schema of configuration file, usable for validation in editors.
+    pub fn json_schema() -> String {
+        let schema = schemars::schema_for!(Config);
+        serde_json::to_string_pretty(&schema).expect("schema is serializable")
+    }
+
+    /// Checks values which can be verified without talking to any API.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        issues_to_result(self.issues(true))
+    }
+
+    /// Like `validate`, but skips Mastodon settings, for commands which only talk to OpenAI.
+    pub fn validate_without_mastodon(&self) -> Result<(), ConfigError> {
+        issues_to_result(self.issues(false))
+    }
+
+    fn issues(&self, mastodon: bool) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if mastodon {
+            issues.extend(self.mastodon_issues());
+        }
+        if crate::language::normalize(&self.mastodon.default_language).is_none() {
+            issues.push(ConfigIssue::new(
+                "mastodon.default_language",
+                format!(
+                    "'{}' is not a valid language code, use e.g. 'en' or 'pt-BR'",
+                    self.mastodon.default_language
+                ),
+            ));
+        }
+        if is_placeholder(&self.gpt.access_token) {
+            issues.push(ConfigIssue::new(
+                "gpt.access_token",
+                "missing, please fill in OpenAI API key",
+            ));
+        }
+        if self.gpt.model.trim().is_empty() || self.gpt.model.contains(char::is_whitespace) {
+            issues.push(ConfigIssue::new(
+                "gpt.model",
+                format!("'{}' is not a valid model name", self.gpt.model),
+            ));
+        }
+        if self.gpt.max_tokens == 0 {
+            issues.push(ConfigIssue::new("gpt.max_tokens", "must be greater than 0"));
+        }
+        for (field, value) in [
+            (
+                "gpt.max_concurrent_requests",
+                Some(self.gpt.limits.max_concurrent_requests),
+            ),
+            (
+                "gpt.requests_per_minute",
+                self.gpt.limits.requests_per_minute,
+            ),
+            ("gpt.tokens_per_minute", self.gpt.limits.tokens_per_minute),
+        ] {
+            if value == Some(0) {
+                issues.push(ConfigIssue::new(field, "must be greater than 0"));
+            }
+        }
+        for (model, price) in &self.gpt.prices {
+            if [price.prompt, price.completion]
+                .iter()
+                .any(|value| value.is_nan() || *value < 0.0)
+            {
+                issues.push(ConfigIssue::new(
+                    &format!("gpt.prices.{}", model),
+                    "prices must not be negative",
+                ));
+            }
+        }
+        if self.manual_refresh.enabled {
+            if self.manual_refresh.interval == 0 {
+                issues.push(ConfigIssue::new(
+                    "manual_refresh.interval",
+                    "must be greater than 0 seconds",
+                ));
+            }
+            for (field, value) in [
+                ("manual_refresh.statuses", self.manual_refresh.statuses),
+                (
+                    "manual_refresh.initial_statuses",
+                    self.manual_refresh.initial_statuses,
+                ),
+            ] {
+                if value == 0 || value > MAX_STATUSES_PER_REQUEST {
+                    issues.push(ConfigIssue::new(
+                        field,
+                        format!("must be between 1 and {}", MAX_STATUSES_PER_REQUEST),
+                    ));
+                }
+            }
+        }
+        if self.backfill.daily_budget == Some(0) {
+            issues.push(ConfigIssue::new(
+                "backfill.daily_budget",
+                "must be greater than 0, remove it to disable the limit",
+            ));
+        }
+        for (field, value) in [
+            ("budget.daily", self.budget.daily),
+            ("budget.monthly", self.budget.monthly),
+        ] {
+            if value.is_some_and(|value| value.is_nan() || value <= 0.0) {
+                issues.push(ConfigIssue::new(field, "must be greater than 0"));
+            }
+        }
+        if (self.budget.daily.is_some() || self.budget.monthly.is_some())
+            && self.get_model_price().is_none()
+        {
+            issues.push(ConfigIssue::new(
+                "gpt.prices",
+                format!(
+                    "price of model '{}' is unknown, budget cannot be enforced",
+                    self.gpt.model
+                ),
+            ));
+        }
+        for language in self
+            .multilingual
+            .languages
+            .iter()
+            .chain(self.multilingual.hashtags.values())
+        {
+            if crate::language::normalize(language).is_none() {
+                issues.push(ConfigIssue::new(
+                    "multilingual",
+                    format!("'{}' is not a valid language code", language),
+                ));
+            }
+        }
+        let mut hashtags: Vec<String> = self
+            .multilingual
+            .hashtags
+            .keys()
+            .map(|hashtag| hashtag.trim_start_matches('#').to_lowercase())
+            .collect();
+        hashtags.sort();
+        for duplicate in hashtags.windows(2).filter(|pair| pair[0] == pair[1]) {
+            issues.push(ConfigIssue::new(
+                "multilingual.hashtags",
+                format!(
+                    "hashtag '{}' is listed more than once, hashtags are case-insensitive",
+                    duplicate[0]
+                ),
+            ));
+        }
+        if self.multilingual.enabled
+            && self.multilingual.languages.is_empty()
+            && !self.multilingual.detect
+        {
+            issues.push(ConfigIssue::new(
+                "multilingual.languages",
+                "list languages or enable detect, otherwise only one language is used",
+            ));
+        }
+        if self.thread_context.enabled && self.thread_context.max_length < 100 {
+            issues.push(ConfigIssue::new(
+                "thread_context.max_length",
+                "must be at least 100 characters",
+            ));
+        }
+        if self.content_warnings.enabled {
+            if !self.gpt.structured_output {
+                issues.push(ConfigIssue::new(
+                    "content_warnings.enabled",
+                    "requires gpt.structured_output to be enabled",
+                ));
+            }
+            if self.content_warnings.topics.is_empty() {
+                issues.push(ConfigIssue::new(
+                    "content_warnings.topics",
+                    "list topics which need content warning",
+                ));
+            }
+        }
+        if self.ocr.enabled {
+            if self.ocr.command.trim().is_empty() {
+                issues.push(ConfigIssue::new("ocr.command", "must not be empty"));
+            }
+            if self.ocr.languages.trim().is_empty() {
+                issues.push(ConfigIssue::new(
+                    "ocr.languages",
+                    "must not be empty, use e.g. 'eng'",
+                ));
+            }
+        }
+        if let Some(model) = &self.quality.fallback_model {
+            if model.trim().is_empty() || model.contains(char::is_whitespace) {
+                issues.push(ConfigIssue::new(
+                    "quality.fallback_model",
+                    format!("'{}' is not a valid model name", model),
+                ));
+            }
+        }
+        if self.cache.max_distance > 64 {
+            issues.push(ConfigIssue::new(
+                "cache.max_distance",
+                "must be between 0 and 64",
+            ));
+        }
+        issues
+    }
+
+    fn mastodon_issues(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        match Url::parse(&self.mastodon.base_url) {
+            Ok(url) => {
+                if url.scheme() != "https" && url.scheme() != "http" {
+                    issues.push(ConfigIssue::new(
+                        "mastodon.base_url",
+                        format!("unsupported scheme '{}', use https", url.scheme()),
+                    ));
+                }
+                if url.host_str().is_none() {
+                    issues.push(ConfigIssue::new("mastodon.base_url", "missing host"));
+                }
+            }
+            Err(err) => issues.push(ConfigIssue::new(
+                "mastodon.base_url",
+                format!("not a valid URL ({})", err),
+            )),
+        }
+        if is_placeholder(&self.mastodon.access_token) {
+            issues.push(ConfigIssue::new(
+                "mastodon.access_token",
+                "missing, please fill in access token of your Mastodon application",
+            ));
+        }
+        if !self.manual_refresh.enabled && !self.streaming.enabled {
+            issues.push(ConfigIssue::new(
+                "streaming.enabled",
+                "both streaming and manual_refresh are disabled, nothing would be processed",
+            ));
+        }
+        issues
+    }
+
+    /// Verifies Mastodon credentials and OpenAI API key / model against the APIs.
+    pub async fn verify_remote(&self) -> Result<(), ConfigError> {
+        let mastodon = Mastodon::from(self.to_mastodon_data());
+        let account = mastodon
+            .verify_credentials()
+            .await
+            .map_err(|err| ConfigError::Remote {
+                service: "Mastodon".to_string(),
+                message: format!("cannot verify credentials ({})", err),
+            })?;
+        log::info!("Mastodon credentials valid for @{}", account.acct);
+
+        verify_openai(&self.gpt.model, &self.gpt.access_token).await
+    }
+
     pub fn to_mastodon_data(&self) -> Data {
         Data {
             base: self.mastodon.base_url.clone().into(),
@@ -74,13 +939,377 @@ impl Config {
     pub fn get_mastodon_access_token(&self) -> String {
         self.mastodon.access_token.clone()
     }
+    /// Normalized default language of descriptions, English if configured one is invalid.
+    pub fn get_default_language(&self) -> String {
+        crate::language::normalize(&self.mastodon.default_language).unwrap_or_else(default_language)
+    }
     pub fn get_max_tokens(&self) -> usize {
         self.gpt.max_tokens
     }
+    pub fn get_structured_output(&self) -> bool {
+        self.gpt.structured_output
+    }
+    pub fn get_batch_images(&self) -> bool {
+        self.gpt.batch_images
+    }
+    pub fn get_gpt_limits(&self) -> GptLimits {
+        self.gpt.limits.clone()
+    }
+    /// Price of configured model, configured prices take precedence over built-in ones.
+    pub fn get_model_price(&self) -> Option<ModelPrice> {
+        self.get_price_of(&self.gpt.model)
+    }
+    pub fn get_price_of(&self, model: &str) -> Option<ModelPrice> {
+        self.gpt
+            .prices
+            .get(model)
+            .copied()
+            .or_else(|| ModelPrice::builtin(model))
+    }
     pub fn get_manual_refresh_config(&self) -> ManualRefreshConfig {
         self.manual_refresh.clone()
     }
     pub fn get_streaming_config(&self) -> StreamingConfig {
         self.streaming.clone()
     }
+    pub fn get_shutdown_timeout(&self) -> u64 {
+        self.general.shutdown_timeout
+    }
+    pub fn get_backfill_config(&self) -> BackfillConfig {
+        self.backfill.clone()
+    }
+    pub fn get_budget_config(&self) -> BudgetConfig {
+        self.budget.clone()
+    }
+    pub fn get_cache_config(&self) -> CacheConfig {
+        self.cache.clone()
+    }
+    pub fn get_quality_config(&self) -> QualityConfig {
+        self.quality.clone()
+    }
+    pub fn get_multilingual_config(&self) -> MultilingualConfig {
+        self.multilingual.clone()
+    }
+    pub fn get_ocr_config(&self) -> OcrConfig {
+        self.ocr.clone()
+    }
+    pub fn get_content_warning_config(&self) -> ContentWarningConfig {
+        self.content_warnings.clone()
+    }
+    pub fn get_thread_context_config(&self) -> ThreadContextConfig {
+        self.thread_context.clone()
+    }
+}
+
+/// Configuration loaded once at startup and shared between loops, swapped atomically on reload.
+#[derive(Debug, Clone)]
+pub struct SharedConfig {
+    path: PathBuf,
+    current: Arc<RwLock<Arc<Config>>>,
+}
+
+impl SharedConfig {
+    pub fn new(path: impl AsRef<Path>, config: Config) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    /// Returns snapshot of current configuration, it stays consistent even if config is reloaded meanwhile.
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads and validates configuration file again, previous config is kept if new one is invalid.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = Config::load(&self.path)?;
+        config.validate()?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Reloads configuration on SIGHUP or when configuration file is modified.
+    pub async fn watch(&self) {
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(hangup) => Some(hangup),
+            Err(err) => {
+                log::warn!(
+                    "Cannot listen for SIGHUP, only file changes will reload config: {}",
+                    err
+                );
+                None
+            }
+        };
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            let reason = tokio::select! {
+                Some(_) = async {
+                    match hangup.as_mut() {
+                        Some(hangup) => hangup.recv().await,
+                        None => std::future::pending::<Option<()>>().await,
+                    }
+                } => "SIGHUP received",
+                _ = interval.tick() => {
+                    let modified = self.modified();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    last_modified = modified;
+                    "configuration file changed"
+                }
+            };
+            match self.reload() {
+                Ok(()) => log::info!("Reloaded {} ({})", self.path.display(), reason),
+                Err(err) => log::error!(
+                    "Cannot reload {} ({}), keeping previous configuration\n{}",
+                    self.path.display(),
+                    reason,
+                    err
+                ),
+            }
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+}
+
+// converts byte offset into 1-based line and column, column counts characters like editors do
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before: String = source
+        .char_indices()
+        .take_while(|(index, _)| *index < offset)
+        .map(|(_, c)| c)
+        .collect();
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+fn issues_to_result(issues: Vec<ConfigIssue>) -> Result<(), ConfigError> {
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid(issues))
+    }
+}
+
+/// Checks that OpenAI accepts the API key and knows the model.
+pub(crate) async fn verify_openai(model: &str, access_token: &str) -> Result<(), ConfigError> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("https://api.openai.com/v1/models/{}", model))
+        .header("Authorization", format!("Bearer {}", access_token))
+        .send()
+        .await
+        .map_err(|err| ConfigError::Remote {
+            service: "OpenAI".to_string(),
+            message: format!("cannot reach API ({})", err),
+        })?;
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(ConfigError::Remote {
+            service: "OpenAI".to_string(),
+            message: "API key was rejected (gpt.access_token)".to_string(),
+        });
+    }
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(ConfigError::Remote {
+            service: "OpenAI".to_string(),
+            message: format!("model '{}' does not exist (gpt.model)", model),
+        });
+    }
+    if !status.is_success() {
+        return Err(ConfigError::Remote {
+            service: "OpenAI".to_string(),
+            message: format!("API returned error, http code: {}", status),
+        });
+    }
+    log::info!("OpenAI API key valid, model '{}' available", model);
+    Ok(())
+}
+
+// config.sample.json ships with tokens made only of 'x'
+pub(crate) fn is_placeholder(value: &str) -> bool {
+    value.trim().is_empty() || value.chars().all(|c| c == 'x')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_JSON: &str = r#"{
+    "mastodon": {"base_url": "https://example.com", "access_token": "token"},
+    "gpt": {"access_token": "key"}
+}"#;
+
+    // writes configuration to temporary file with given extension and loads it
+    fn load(name: &str, extension: &str, contents: &str) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!(
+            "masto_vision_test_{}_{}.{}",
+            std::process::id(),
+            name,
+            extension
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let config = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    fn parse_error(result: Result<Config, ConfigError>) -> (String, Option<(usize, usize)>) {
+        match result {
+            Err(ConfigError::Parse {
+                field, location, ..
+            }) => (field, location),
+            other => panic!("expected parse error, got {:?}", other),
+        }
+    }
+
+    fn issue_fields(result: Result<(), ConfigError>) -> Vec<String> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(issues)) => {
+                issues.into_iter().map(|issue| issue.field).collect()
+            }
+            Err(err) => panic!("expected validation issues, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn json_error_reports_field_and_location() {
+        let config = r#"{
+    "mastodon": {"base_url": "https://example.com", "access_token": "token"},
+    "gpt": {"access_token": "key", "max_tokens": "many"}
+}"#;
+        let (field, location) = parse_error(load("json_error", "json", config));
+        assert_eq!(field, "gpt.max_tokens");
+        assert_eq!(location.map(|(line, _)| line), Some(3));
+    }
+
+    #[test]
+    fn toml_error_reports_field_and_location() {
+        let config = "[mastodon]\nbase_url = \"https://example.com\"\naccess_token = \"token\"\n\n[gpt]\naccess_token = \"key\"\nmax_tokens = \"many\"\n";
+        let (field, location) = parse_error(load("toml_error", "toml", config));
+        assert_eq!(field, "gpt.max_tokens");
+        assert_eq!(location, Some((7, 14)));
+    }
+
+    #[test]
+    fn yaml_error_reports_field_and_location() {
+        let config = "mastodon:\n  base_url: https://example.com\n  access_token: token\ngpt:\n  access_token: key\n  max_tokens: many\n";
+        let (field, location) = parse_error(load("yaml_error", "yaml", config));
+        assert_eq!(field, "gpt.max_tokens");
+        assert_eq!(location.map(|(line, _)| line), Some(6));
+    }
+
+    #[test]
+    fn missing_required_field_is_reported() {
+        let config =
+            r#"{"mastodon": {"base_url": "https://example.com"}, "gpt": {"access_token": "key"}}"#;
+        let (field, _) = parse_error(load("missing_field", "json", config));
+        assert_eq!(field, "mastodon");
+    }
+
+    #[test]
+    fn unknown_extension_is_rejected() {
+        assert!(matches!(
+            load("unknown", "ini", MINIMAL_JSON),
+            Err(ConfigError::UnknownFormat(_))
+        ));
+    }
+
+    #[test]
+    fn missing_sections_use_defaults() {
+        let config = load("defaults", "json", MINIMAL_JSON).unwrap();
+        assert_eq!(config.get_model(), default_model());
+        assert_eq!(config.get_max_tokens(), default_max_tokens());
+        assert_eq!(config.get_default_language(), "en");
+        assert_eq!(config.get_gpt_limits().max_concurrent_requests, 4);
+        assert_eq!(config.get_cache_config().ttl_days, 30);
+        assert_eq!(config.get_budget_config().daily, None);
+        assert_eq!(config.get_shutdown_timeout(), 30);
+    }
+
+    #[test]
+    fn formats_load_the_same_configuration() {
+        let toml = "[mastodon]\nbase_url = \"https://example.com\"\naccess_token = \"token\"\n\n[gpt]\naccess_token = \"key\"\n";
+        let yaml = "mastodon:\n  base_url: https://example.com\n  access_token: token\ngpt:\n  access_token: key\n";
+        for config in [
+            load("same_json", "json", MINIMAL_JSON),
+            load("same_toml", "toml", toml),
+            load("same_yaml", "yml", yaml),
+        ] {
+            let config = config.unwrap();
+            assert_eq!(config.get_gpt_api_key(), "key");
+            assert!(issue_fields(config.validate()).is_empty());
+        }
+    }
+
+    #[test]
+    fn placeholder_tokens_are_reported() {
+        let config = Config::new(
+            "https://example.com".to_string(),
+            String::new(),
+            String::new(),
+            "xxxx".to_string(),
+            "xxxx".to_string(),
+        );
+        assert_eq!(
+            issue_fields(config.validate()),
+            ["mastodon.access_token", "gpt.access_token"]
+        );
+        assert_eq!(
+            issue_fields(config.validate_without_mastodon()),
+            ["gpt.access_token"]
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported_with_message() {
+        let mut config = Config::new(
+            "ftp://example.com".to_string(),
+            String::new(),
+            String::new(),
+            "token".to_string(),
+            "key".to_string(),
+        );
+        config.gpt.max_tokens = 0;
+        let Err(ConfigError::Invalid(issues)) = config.validate() else {
+            panic!("expected validation issues");
+        };
+        let messages: Vec<_> = issues
+            .iter()
+            .map(|issue| (issue.field.as_str(), issue.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                ("mastodon.base_url", "unsupported scheme 'ftp', use https"),
+                ("gpt.max_tokens", "must be greater than 0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_and_column_count_characters() {
+        let source = "a = 1\nzażółć = \"x\"";
+        let offset = source.find('=').unwrap();
+        assert_eq!(line_and_column(source, offset), (1, 3));
+        let offset = source.rfind('=').unwrap();
+        assert_eq!(line_and_column(source, offset), (2, 8));
+        assert_eq!(line_and_column(source, 0), (1, 1));
+        assert_eq!(line_and_column(source, source.len() + 10), (2, 13));
+    }
 }
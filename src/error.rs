@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+use crate::config::ConfigError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// All errors which can be returned by the crate.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("Network error: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("Vision API returned error, http status: {status}, body: {body}")]
+    ProviderApi { status: StatusCode, body: String },
+    #[error("Vision API refused to describe the image: {0}")]
+    ContentPolicy(String),
+    #[error("Model returned unusable description: {0}")]
+    LowQuality(String),
+    #[error("Unexpected response from {service}: {message}")]
+    InvalidResponse {
+        service: &'static str,
+        message: String,
+    },
+    #[error("Mastodon API returned error, http status: {status}, body: {body}")]
+    MastodonApi { status: StatusCode, body: String },
+    #[error("Mastodon client error: {0}")]
+    MastodonClient(#[from] mastodon_async::Error),
+    #[error("Cannot access state file {path}: {message}")]
+    StateStore { path: String, message: String },
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("Operation cancelled")]
+    Cancelled,
+    #[error("In-flight work did not finish within {} seconds and was abandoned", .0.as_secs())]
+    ShutdownTimeout(Duration),
+}
+
+impl Error {
+    /// Network errors, rate limiting and server errors may go away on retry, others won't
+    /// (e.g. deleted status or rejected credentials).
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Network(_) => true,
+            Error::ProviderApi { status, .. } | Error::MastodonApi { status, .. } => {
+                *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || status.is_server_error()
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn state_store(path: &str, err: impl std::fmt::Display) -> Self {
+        Error::StateStore {
+            path: path.to_string(),
+            message: err.to_string(),
+        }
+    }
+
+    pub(crate) fn invalid_response(service: &'static str, message: impl Into<String>) -> Self {
+        Error::InvalidResponse {
+            service,
+            message: message.into(),
+        }
+    }
+}
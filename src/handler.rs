@@ -1,9 +1,26 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::time::Duration;
-use std::{collections::HashMap, error::Error, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
-use crate::shared_data::SHARED_DATA;
-use crate::{config::Config, mastodon_patch::MastodonPatch, vision::Vision};
-use chrono::Local;
+use crate::backfill::{BackfillOptions, BackfillState};
+use crate::cache::{self, DescriptionCache, DESCRIPTION_CACHE};
+use crate::cli::parse_status_id;
+use crate::config::{Config, ContentWarningAction, MultilingualConfig, OcrMode, SharedConfig};
+use crate::error::{Error, Result};
+use crate::in_flight::{Claim, InFlight, QueueState};
+use crate::language::{self, shorten, MAX_DESCRIPTION_LENGTH};
+use crate::mastodon_patch::MastodonPatch;
+use crate::ocr;
+use crate::rate_limit::VisionLimiter;
+use crate::report::DryRunReport;
+use crate::shared_data::{SharedData, SHARED_DATA};
+use crate::thread::ThreadContext;
+use crate::usage::{BudgetExceeded, UsageLedger, UsageTotal, USAGE};
+use crate::vision::{Description, Vision};
+use chrono::{DateTime, Local};
 
 use futures_util::{StreamExt, TryFutureExt, TryStreamExt};
 use kv_log_macro::warn;
@@ -11,51 +28,90 @@ use log::{debug, error, info, LevelFilter};
 use mastodon_async::entities::event::Event;
 use mastodon_async::{prelude::*, Mastodon};
 
+use mastodon_async::entities::account::Account;
 use mastodon_async::entities::status::Status;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use voca_rs::strip::strip_tags;
 
-use clap::{builder::PossibleValue, Arg};
+const BACKFILL_PAGE_SIZE: usize = 40;
+// how often held back work checks whether budget was raised by configuration reload
+const BUDGET_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+// cache hit and miss counts are saved this often even if no new description is stored
+const CACHE_FLUSH_INTERVAL: Duration = Duration::from_secs(600);
+// queue shown by `status` is saved at most this often, instead of on every change
+const QUEUE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What happened to images of a status, tells backfill whether it can move past it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Outcome {
+    /// Images which had no description.
+    pub needed: usize,
+    /// Images whose new description was published.
+    pub described: usize,
+    /// Images which the model refused or kept describing unusably, asking again would not help.
+    pub permanently_failed: usize,
+}
+
+impl Outcome {
+    /// Every image was either described or can never be.
+    pub fn is_settled(&self) -> bool {
+        self.described + self.permanently_failed >= self.needed
+    }
+}
 
 #[derive(Clone)]
-pub struct Handler();
+pub struct Handler {
+    config: SharedConfig,
+    dry_run: Option<Arc<DryRunReport>>,
+    /// Statuses already written to dry-run report, they are not saved as parsed.
+    dry_run_handled: Arc<Mutex<HashSet<String>>>,
+    cancel: CancellationToken,
+    abort: CancellationToken,
+    tracker: TaskTracker,
+    in_flight: Arc<InFlight>,
+    /// Statuses held back until spending budget resets.
+    held: Arc<AtomicUsize>,
+    /// Whether queue changed since it was last saved for `status` command.
+    queue_changed: Arc<AtomicBool>,
+    limiter: Arc<VisionLimiter>,
+}
+
 impl Handler {
-    fn get_log_level(&self) -> LevelFilter {
-        let matches = clap::Command::new("MastoVision")
-            .version("0.1.0")
-            .author("pecet")
-            .about("Generates image descriptions for Mastodon")
-            .arg(
-                Arg::new("verbosity level")
-                    .short('v')
-                    .long("verbosity")
-                    .value_parser([
-                        PossibleValue::new("info"),
-                        PossibleValue::new("debug"),
-                        PossibleValue::new("trace"),
-                        PossibleValue::new("warn"),
-                        PossibleValue::new("error"),
-                        PossibleValue::new("quiet"),
-                    ])
-                    .default_value("info"),
-            )
-            .get_matches();
-        // convert matches to LevelFilter
-        matches
-            .get_one("verbosity level")
-            .cloned()
-            .map_or(LevelFilter::Info, |level: String| match level.as_str() {
-                "info" => LevelFilter::Info,
-                "debug" => LevelFilter::Debug,
-                "trace" => LevelFilter::Trace,
-                "warn" => LevelFilter::Warn,
-                "error" => LevelFilter::Error,
-                "quiet" => LevelFilter::Off,
-                _ => LevelFilter::Info,
-            })
+    pub fn new(config: SharedConfig) -> Self {
+        // limits are taken from configuration at startup, reload does not change them
+        let limiter = Arc::new(VisionLimiter::from_config(&config.get()));
+        Self {
+            config,
+            limiter,
+            dry_run: None,
+            dry_run_handled: Arc::new(Mutex::new(HashSet::new())),
+            cancel: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+            in_flight: Arc::new(InFlight::new()),
+            held: Arc::new(AtomicUsize::new(0)),
+            queue_changed: Arc::new(AtomicBool::new(false)),
+        }
     }
 
-    pub fn setup_logging(&self) -> Result<(), fern::InitError> {
-        let log_level = self.get_log_level();
+    /// Token shared by all loops and waits, cancelling it interrupts them.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
 
+    /// Waits without blocking worker thread, returns false if cancelled meanwhile.
+    async fn wait(&self, duration: Duration) -> bool {
+        wait_or_cancel(&self.cancel, duration).await
+    }
+
+    /// Descriptions are generated but only written to the report, statuses are never edited.
+    pub fn with_dry_run(mut self, report: Arc<DryRunReport>) -> Self {
+        self.dry_run = Some(report);
+        self
+    }
+
+    pub fn setup_logging(log_level: LevelFilter) -> std::result::Result<(), fern::InitError> {
         fern::Dispatch::new()
             // Format the output
             .format(|out, message, record| {
@@ -86,7 +142,242 @@ impl Handler {
         Ok(())
     }
 
-    async fn handle_update(&self, update: Status, user_id: String) {
+    /// Holds work back while spending cap is reached, returns false if cancelled meanwhile.
+    async fn wait_for_budget(&self) -> bool {
+        let mut held = false;
+        let release = |held: bool| {
+            if held {
+                self.held.fetch_sub(1, Ordering::Relaxed);
+                self.queue_changed.store(true, Ordering::Relaxed);
+            }
+        };
+        loop {
+            let config = self.config.get();
+            let Some(exceeded) = USAGE
+                .lock()
+                .unwrap()
+                .exceeded_cap(&config.get_budget_config())
+            else {
+                release(held);
+                return true;
+            };
+            if !held {
+                held = true;
+                self.held.fetch_add(1, Ordering::Relaxed);
+                self.queue_changed.store(true, Ordering::Relaxed);
+            }
+            let first = USAGE.lock().unwrap().mark_notified(&exceeded);
+            if first {
+                warn!(
+                    "{}, descriptions are paused until the budget resets",
+                    exceeded
+                );
+                if config.get_budget_config().notify {
+                    self.notify_budget(config, &exceeded).await;
+                }
+            }
+            let resets_in = exceeded.resets_in();
+            debug!(
+                "Budget resets in {} seconds, holding status back",
+                resets_in.as_secs()
+            );
+            if !self.wait(resets_in.min(BUDGET_RECHECK_INTERVAL)).await {
+                release(held);
+                return false;
+            }
+        }
+    }
+
+    /// Saves queue for `status` command, only done by `run`, from single task.
+    fn save_queue_state(&self) {
+        let state = QueueState {
+            in_flight: self.in_flight.len(),
+            held: self.held.load(Ordering::Relaxed),
+            updated: Some(Local::now()),
+        };
+        if let Err(err) = state.save() {
+            error!("{}", err);
+        }
+    }
+
+    async fn notify_budget(&self, config: Arc<Config>, exceeded: &BudgetExceeded) {
+        let text = format!(
+            "MastoVision: {}. New image descriptions are paused and will resume automatically when the budget resets.",
+            exceeded
+        );
+        if let Err(err) = MastodonPatch::new(config)
+            .with_dry_run(self.dry_run.clone())
+            .post_direct_message(text)
+            .await
+        {
+            error!("Cannot send budget notification: {}", err);
+        }
+    }
+
+    /// Text of the status with its content warning, preceding posts of the thread and quoted post.
+    /// `status` is raw JSON of the status, as quotes are not part of Status entity.
+    async fn thread_context(
+        &self,
+        config: Arc<Config>,
+        mp: &MastodonPatch,
+        update: &Status,
+        status: &str,
+    ) -> String {
+        let thread_config = config.get_thread_context_config();
+        if !thread_config.enabled {
+            return update.content.clone();
+        }
+        let mut context = ThreadContext {
+            spoiler_text: update.spoiler_text.clone(),
+            ..ThreadContext::default()
+        };
+        if update.in_reply_to_id.is_some() && thread_config.max_ancestors > 0 {
+            match mp.get_context_of_message(update.id.to_string()).await {
+                Ok(thread) => {
+                    context = context.with_ancestors(&thread, thread_config.max_ancestors)
+                }
+                Err(err) => warn!("Cannot get thread of message {}: {}", update.id, err),
+            }
+        }
+        match serde_json::from_str(status) {
+            Ok(status) => context = context.with_quote(&status),
+            Err(err) => warn!("Cannot parse message {}: {}", update.id, err),
+        }
+        let prompt = context.to_prompt(&update.content, &thread_config);
+        debug!("Context of message {}: {}", update.id, prompt);
+        prompt
+    }
+
+    /// Describes all images of the status in one request per language. Returns descriptions by
+    /// attachment ID, or nothing if it fails and images have to be described one by one.
+    async fn describe_together(
+        &self,
+        config: Arc<Config>,
+        images: &[(String, String)],
+        languages: &[String],
+        context: &str,
+    ) -> HashMap<String, Description> {
+        let image_urls: Vec<String> = images.iter().map(|(_, url)| url.clone()).collect();
+        let mut descriptions: Vec<Vec<(String, Description)>> = vec![Vec::new(); images.len()];
+        for lang in languages {
+            let mut retry: u64 = 0;
+            let batch = loop {
+                retry += 1;
+                debug!(
+                    "Generating {} descriptions of {} images together, retry: {}",
+                    lang,
+                    images.len(),
+                    retry
+                );
+                let result = Vision::new(config.clone())
+                    .with_limiter(self.limiter.clone())
+                    .get_descriptions(image_urls.clone(), lang.clone(), context.to_string())
+                    .await;
+                match result {
+                    Ok(batch) => break batch,
+                    Err(err) => {
+                        error!("Failed to describe images together: {}", err);
+                        if retry >= 3 || !err.is_retryable() {
+                            warn!("Describing images one by one instead");
+                            return HashMap::new();
+                        }
+                        if !wait_or_cancel(&self.abort, Duration::from_millis(2000)).await {
+                            return HashMap::new();
+                        }
+                    }
+                }
+            };
+            for (image_descriptions, description) in descriptions.iter_mut().zip(batch) {
+                image_descriptions.push((lang.clone(), description));
+            }
+        }
+        let separator = config.get_multilingual_config().separator;
+        images
+            .iter()
+            .zip(descriptions)
+            .map(|((attachment_id, _), descriptions)| {
+                let description = combine_languages(descriptions, &separator);
+                info!(
+                    "Generated description for attachment {}: {}",
+                    attachment_id, description.alt_text
+                );
+                (attachment_id.clone(), description)
+            })
+            .collect()
+    }
+
+    async fn suggest_content_warning(
+        &self,
+        mp: &MastodonPatch,
+        update: &Status,
+        topics: &[String],
+    ) {
+        let has_content_warning = !update.spoiler_text.is_empty();
+        info!(
+            "Suggesting content warning for message {}: {}",
+            update.id,
+            topics.join(", ")
+        );
+        let text = format!(
+            "MastoVision: images in {} may need {}: {}",
+            update.url.clone().unwrap_or_else(|| update.id.to_string()),
+            if has_content_warning {
+                "another topic in content warning"
+            } else {
+                "content warning"
+            },
+            topics.join(", ")
+        );
+        if let Err(err) = mp.post_direct_message(text).await {
+            error!("Cannot send content warning suggestion: {}", err);
+        }
+    }
+
+    /// Generates missing descriptions for images of own status.
+    /// If the same status is already being handled, waits for that instead of starting again.
+    async fn handle_update(&self, update: Status, user_id: String) -> Outcome {
+        // statuses of followed accounts arrive too, only own ones are described
+        if format!("{}", update.account.id) != user_id {
+            return Outcome::default();
+        }
+        let status_id = update.id.to_string();
+        match self.in_flight.claim(&status_id) {
+            Claim::Owned(guard) => {
+                self.queue_changed.store(true, Ordering::Relaxed);
+                let outcome = self.describe_update(update).await;
+                guard.finish(outcome.described);
+                self.queue_changed.store(true, Ordering::Relaxed);
+                outcome
+            }
+            Claim::InProgress(mut receiver) => {
+                debug!(
+                    "Status {} is already being handled, waiting for result",
+                    status_id
+                );
+                let described = receiver
+                    .wait_for(|described| described.is_some())
+                    .await
+                    .map(|described| (*described).unwrap_or_default())
+                    .unwrap_or_default();
+                debug!(
+                    "Status {} handled by another task, descriptions added: {}",
+                    status_id, described
+                );
+                // descriptions are counted by the task which generated them,
+                // but if it added none, the status still needs another attempt
+                Outcome {
+                    needed: if described == 0 {
+                        images_to_describe(&update).len()
+                    } else {
+                        0
+                    },
+                    ..Outcome::default()
+                }
+            }
+        }
+    }
+
+    async fn describe_update(&self, update: Status) -> Outcome {
         debug!("Update event received:\n{:#?}", &update);
         {
             // if it does not contain this update, then make sure
@@ -94,104 +385,248 @@ impl Handler {
             let already_parsed = SHARED_DATA.lock().unwrap().already_parsed.clone();
             if already_parsed.contains(&update.id.to_string()) {
                 debug!("Already handled update, skipping");
-                return;
+                return Outcome::default();
             }
         }
-        let lang = update.language.clone().unwrap_or("en".to_string());
-        let context = update.content.clone();
-        let lang_arc = Arc::new(lang.clone());
-        let context_arc = Arc::new(context.clone());
-        if format!("{}", update.account.id) == user_id {
+        if self
+            .dry_run_handled
+            .lock()
+            .unwrap()
+            .contains(&update.id.to_string())
+        {
+            debug!("Update already written to dry-run report, skipping");
+            return Outcome::default();
+        }
+        let images = images_to_describe(&update);
+        let mut outcome = Outcome {
+            needed: images.len(),
+            ..Outcome::default()
+        };
+        if !images.is_empty() {
+            if !self.wait_for_budget().await {
+                info!("Status {} left undescribed on shutdown", update.id);
+                return outcome;
+            }
+            let config = self.config.get();
+            let message_id = update.id.to_string();
+            let mp = MastodonPatch::new(config.clone())
+                .with_dry_run(self.dry_run.clone())
+                .with_cancellation(self.abort.clone());
+            // only gives context, generating takes a while and the status may be edited meanwhile
+            let current_json = match mp
+                .get_json_of_message_with_retry(message_id.clone(), 10)
+                .await
+            {
+                Ok(current_json) => current_json,
+                Err(err) => {
+                    error!(
+                        "Cannot get message {}, not updating it: {}",
+                        message_id, err
+                    );
+                    return outcome;
+                }
+            };
+            let lang = language::resolve(
+                update.language.as_deref(),
+                &strip_tags(&update.content),
+                &config.get_default_language(),
+            );
+            debug!("Language of descriptions: {}", lang);
+            let languages =
+                description_languages(&update, &lang, &config.get_multilingual_config());
+            let context = self
+                .thread_context(config.clone(), &mp, &update, &current_json)
+                .await;
+            let batched = Arc::new(if config.get_batch_images() && images.len() > 1 {
+                self.describe_together(config.clone(), &images, &languages, &context)
+                    .await
+            } else {
+                HashMap::new()
+            });
             let attachments: Vec<_> = update.media_attachments.clone().into_iter().collect();
-            let handles: Vec<_> = attachments.into_iter().map(|attachment| {
-                let lang_arc_clone = lang_arc.clone();
-                let context_arc = context_arc.clone();
-                tokio::spawn(async move {
-                    if attachment.media_type == MediaType::Image &&
-                        (attachment.description.is_none() || attachment.description.unwrap().is_empty()) {
-                            if let Some(url) = attachment.url.clone() {
-                                let lang = lang_arc_clone.as_ref().clone();
-                                let context = context_arc.as_ref().clone();
-                                let mut retry: u64 = 0;
-                                let attachment_id = attachment.id.clone();
-                                let attachment_url = attachment.url.clone().unwrap();
-                                loop {
-                                    retry += 1;
-                                    debug!("Generating description for attachment {} with URL: {}", &attachment_id, &attachment_url);
-                                    debug!("Retry: {}", retry);
-                                    let result = Vision().get_description(url.clone(), lang.clone(), context.clone()).await;
-                                    match result {
-                                        Ok(ref description) => {
-                                            info!("Generated description for attachment {}: {}", attachment.id, description);
-                                            return (attachment.id.clone(), Some(description.clone()))
-                                        },
-                                        Err(ref err) => {
-                                            error!("Failed to generate description for attachment {}: {:#?}", attachment.id, err);
-                                            if retry >= 10 {
-                                                error!("Maximum retry count reached, giving up");
-                                                return (attachment.id.clone(), None);
-                                            }
-                                            error!("Retrying after slight delay");
-                                            std::thread::sleep(Duration::from_millis(2000));
-                                        }
-                                    };
-                                }
-
-                            } else {
-                                warn!("Cannot get URL for attachment {}", attachment.id);
+            let attachment_ids: Vec<_> = attachments
+                .iter()
+                .map(|attachment| attachment.id.clone())
+                .collect();
+            let handles: Vec<_> = attachments
+                .into_iter()
+                .map(|attachment| {
+                    let languages = languages.clone();
+                    let context = context.clone();
+                    let config = config.clone();
+                    // in-flight work is only interrupted when shutdown deadline passes
+                    let abort = self.abort.clone();
+                    let limiter = self.limiter.clone();
+                    let batched = batched.clone();
+                    tokio::spawn(async move {
+                        let attachment_id = attachment.id.clone();
+                        if let Some(description) = batched.get(&attachment_id.to_string()) {
+                            return (attachment_id, Some(Ok(description.clone())));
+                        }
+                        if attachment.media_type != MediaType::Image
+                            || !attachment.description.unwrap_or_default().is_empty()
+                        {
+                            return (attachment_id, None);
+                        }
+                        let Some(url) = attachment.url else {
+                            warn!("Cannot get URL for attachment {}", attachment_id);
+                            return (attachment_id, None);
+                        };
+                        let image = Image::prepare(&config, attachment_id.to_string(), url).await;
+                        let mut descriptions = Vec::new();
+                        for lang in &languages {
+                            let description = describe_image(
+                                config.clone(),
+                                limiter.clone(),
+                                &abort,
+                                &image,
+                                lang.clone(),
+                                context.clone(),
+                            )
+                            .await;
+                            match description {
+                                Ok(description) => descriptions.push((lang.clone(), description)),
+                                // partially translated alt text would be misleading
+                                Err(err) => return (attachment_id, Some(Err(err))),
                             }
-                    }
-                    (attachment.id.clone(), None)
+                        }
+                        let mut description = combine_languages(
+                            descriptions,
+                            &config.get_multilingual_config().separator,
+                        );
+                        if let Some(transcription) = &image.transcription {
+                            if config.get_ocr_config().mode == OcrMode::Transcribe {
+                                // label would be in English, so it's only added to English alt text
+                                let labeled = languages.iter().all(|lang| lang.starts_with("en"));
+                                description.alt_text = append_transcription(
+                                    &description.alt_text,
+                                    &transcription.text,
+                                    labeled,
+                                );
+                            }
+                        }
+                        (attachment_id, Some(Ok(description)))
+                    })
                 })
-            }).collect();
+                .collect();
             let results = futures_util::future::join_all(handles)
                 .await
                 .into_iter()
-                .map(|res| res.expect("Task panicked"));
-            let descriptions: Vec<_> = results.collect();
+                .zip(attachment_ids)
+                .map(|(res, attachment_id)| {
+                    res.unwrap_or_else(|err| {
+                        error!(
+                            "Describing attachment {} failed unexpectedly: {}",
+                            attachment_id, err
+                        );
+                        let err = Error::Io(std::io::Error::other(err));
+                        (attachment_id, Some(Err(err)))
+                    })
+                });
+            // attachments which didn't need description are left out
+            let results: Vec<_> = results
+                .filter_map(|(attachment_id, result)| Some((attachment_id, result?)))
+                .collect();
+            let descriptions: Vec<_> = results
+                .iter()
+                .filter_map(|(attachment_id, result)| Some((attachment_id, result.as_ref().ok()?)))
+                .collect();
             debug!("Number of descriptions got: {}", &descriptions.len());
+            outcome.permanently_failed = results
+                .iter()
+                .filter(|(_, result)| result.as_ref().is_err_and(is_permanent))
+                .count();
+            for (attachment_id, description) in &descriptions {
+                log_details(attachment_id.as_ref(), description);
+            }
             let descriptions_filtered: HashMap<_, _> = descriptions
                 .iter()
-                .filter(|d| d.1.is_some())
-                .map(|d| (d.0.to_string(), d.1.clone().unwrap()))
+                .map(|(attachment_id, description)| {
+                    (attachment_id.to_string(), description.alt_text.clone())
+                })
                 .collect();
-            debug!(
-                "Number of non-empty descriptions got: {}",
-                descriptions_filtered.len()
-            );
-            if descriptions.len() == descriptions_filtered.len() {
+            if descriptions_filtered.len() == outcome.needed {
                 debug!("All descriptions generated successfully");
             } else {
                 debug!(
-                    "Some descriptions failed to generate: {}",
-                    descriptions.len() - descriptions_filtered.len()
+                    "Some descriptions failed to generate: {}, permanently: {}",
+                    outcome.needed - descriptions_filtered.len(),
+                    outcome.permanently_failed
                 );
             }
-            let message_id = update.clone().id.to_string();
             if descriptions_filtered.is_empty() {
                 debug!("No descriptions generated for message {}", message_id);
-                return;
+                return outcome;
             }
-            // TODO: avoid creating new instance of Config here
-            let mp = MastodonPatch::new(Config::from_json());
-            let current_json = mp
+            let cw_config = config.get_content_warning_config();
+            let topics = if cw_config.enabled {
+                content_warning_topics(
+                    descriptions.iter().map(|(_, description)| *description),
+                    &cw_config.topics,
+                )
+            } else {
+                Vec::new()
+            };
+            let content_warning = match cw_config.action {
+                ContentWarningAction::Apply if !topics.is_empty() => {
+                    info!(
+                        "Adding content warning to message {}: {}",
+                        message_id,
+                        topics.join(", ")
+                    );
+                    Some(topics.join(", "))
+                }
+                _ => None,
+            };
+            // update is based on the newest version, so edits made while describing are kept
+            let current_json = match mp
                 .get_json_of_message_with_retry(message_id.clone(), 10)
                 .await
-                .unwrap_or_default()
-                .unwrap_or_default();
-            mp.put_json_of_message_with_retry(
-                current_json,
-                message_id.clone(),
-                descriptions_filtered.clone(),
-                10,
-            )
-            .await
-            .unwrap();
+            {
+                Ok(current_json) => current_json,
+                Err(err) => {
+                    error!(
+                        "Cannot get message {}, not updating it: {}",
+                        message_id, err
+                    );
+                    return outcome;
+                }
+            };
+            if let Err(err) = mp
+                .put_json_of_message_with_retry(
+                    current_json,
+                    message_id.clone(),
+                    descriptions_filtered.clone(),
+                    content_warning,
+                    10,
+                )
+                .await
+            {
+                error!("Cannot update message {}: {}", message_id, err);
+                return outcome;
+            }
+            outcome.described = descriptions_filtered.len();
+            if cw_config.action == ContentWarningAction::Suggest && !topics.is_empty() {
+                self.suggest_content_warning(&mp, &update, &topics).await;
+            }
 
-            if descriptions.len() == descriptions_filtered.len() {
+            if self.dry_run.is_some() {
+                info!(
+                    "Dry run, description for message {} written to report",
+                    message_id
+                );
+                self.dry_run_handled
+                    .lock()
+                    .unwrap()
+                    .insert(message_id.clone());
+                return outcome;
+            }
+            if outcome.described == outcome.needed {
                 let mut shared_data = SHARED_DATA.lock().unwrap();
                 shared_data.already_parsed.insert(update.id.to_string());
-                shared_data.save();
+                if let Err(err) = shared_data.save() {
+                    error!("{}", err);
+                }
             }
             debug!("Saved parsed status ID to shared data");
             debug!(
@@ -200,14 +635,281 @@ impl Handler {
             );
 
             info!("Successfully added description to message {}", message_id);
+            return outcome;
         }
+        Outcome::default()
     }
 
-    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
-        let self_arc = Arc::new(self.clone());
-        let self_clone = self_arc.clone();
-        let self_clone2 = self_arc.clone();
-        let streaming_loop = tokio::spawn(async move {
+    /// Validates configuration file at given path and verifies credentials against the APIs.
+    pub async fn check_config(path: &str) -> Result<()> {
+        info!("Checking configuration file: {}", path);
+        let config = Config::load(path)?;
+        config.validate()?;
+        info!("Configuration file is valid, verifying credentials");
+        config.verify_remote().await?;
+        info!("Configuration OK");
+        Ok(())
+    }
+
+    /// Loads all persisted state, so damaged state files stop the program at start
+    /// instead of being replaced with empty state, e.g. spend counted against budget caps.
+    pub fn check_state() -> Result<()> {
+        SharedData::load()?;
+        UsageLedger::load()?;
+        DescriptionCache::load()?;
+        Ok(())
+    }
+
+    async fn login(&self) -> Result<(Mastodon, Account)> {
+        let mastodon = Mastodon::from(self.config.get().to_mastodon_data());
+        log::info!("Logging in to Mastodon");
+        let you = mastodon.verify_credentials().await?;
+        log::info!("Logged in");
+        log::debug!("Logged in as user id: {}", you.id);
+        Ok((mastodon, you))
+    }
+
+    /// Describes images of single status immediately, status can be given as ID or URL.
+    pub async fn process_status(&self, status: &str) -> Result<()> {
+        let status_id = parse_status_id(status).ok_or_else(|| {
+            Error::InvalidInput(format!("'{}' is not a status ID or URL", status))
+        })?;
+        let (_, you) = self.login().await?;
+        let mp = MastodonPatch::new(self.config.get());
+        let json = mp.get_json_of_message(status_id.clone()).await?;
+        let status: Status = serde_json::from_str(&json)?;
+        self.handle_update(status, format!("{}", you.id)).await;
+        cache::flush();
+        Ok(())
+    }
+
+    /// Walks whole history of statuses with media, from newest to oldest, resuming from saved cursor.
+    pub async fn backfill(&self, options: BackfillOptions) -> Result<()> {
+        let (_, you) = self.login().await?;
+        let user_id = format!("{}", &you.id);
+        let mp = MastodonPatch::new(self.config.get());
+        // dry run must not move the cursor, otherwise real run would skip reviewed statuses
+        let persist = self.dry_run.is_none();
+        let mut state = if options.restart {
+            BackfillState::default()
+        } else {
+            BackfillState::load()?
+        };
+        if state.finished {
+            log::info!("Backfill already finished, use --restart to run it again");
+            return Ok(());
+        }
+        let daily_budget =
+            options
+                .daily_budget
+                .or(self.config.get().get_backfill_config().daily_budget);
+        if let Some(cursor) = &state.cursor {
+            log::info!("Resuming backfill from status {}", cursor);
+        }
+        let cancel = self.cancel.clone();
+        let signal_listener = tokio::spawn(async move {
+            let signal = shutdown_signal().await;
+            info!(
+                "{} received, stopping backfill after current status",
+                signal
+            );
+            cancel.cancel();
+        });
+        'pages: loop {
+            let page = mp
+                .get_media_statuses_of_account(
+                    user_id.clone(),
+                    state.cursor.clone(),
+                    None,
+                    BACKFILL_PAGE_SIZE,
+                )
+                .await?;
+            if page.is_empty() {
+                log::info!("Reached the oldest status, backfill finished");
+                state.finished = true;
+                if persist {
+                    state.save()?;
+                }
+                break;
+            }
+            for value in page {
+                if self.cancel.is_cancelled() {
+                    break 'pages;
+                }
+                let posted = value
+                    .get("created_at")
+                    .and_then(|created_at| created_at.as_str())
+                    .and_then(|created_at| DateTime::parse_from_rfc3339(created_at).ok())
+                    .map(|created_at| created_at.with_timezone(&Local).date_naive());
+                let status: Status = serde_json::from_value(value)?;
+                let status_id = status.id.to_string();
+                if let Some(posted) = posted {
+                    if options.until.is_some_and(|until| posted > until) {
+                        state.cursor = Some(status_id);
+                        continue;
+                    }
+                    if options.since.is_some_and(|since| posted < since) {
+                        log::info!(
+                            "Reached statuses older than requested range, backfill finished"
+                        );
+                        state.finished = true;
+                        if persist {
+                            state.save()?;
+                        }
+                        break 'pages;
+                    }
+                }
+                let pending = images_to_describe(&status).len();
+                if let Some(remaining) = state.remaining_budget(daily_budget) {
+                    // always let at least one status through on a new day, even if it has more images than budget
+                    if remaining == 0 || (pending > remaining && state.described_today > 0) {
+                        log::info!(
+                            "Daily budget of descriptions used up ({} today), run backfill again tomorrow to resume",
+                            state.described_today
+                        );
+                        if persist {
+                            state.save()?;
+                        }
+                        break 'pages;
+                    }
+                }
+                let outcome = self.handle_update(status, user_id.clone()).await;
+                state.described_today += outcome.described;
+                if self.cancel.is_cancelled() {
+                    // status may have been interrupted, e.g. while waiting for spending budget
+                    log::info!("Backfill stopped before status {} was handled", status_id);
+                    if persist {
+                        state.save()?;
+                    }
+                    break 'pages;
+                }
+                if !outcome.is_settled() {
+                    log::warn!(
+                        "Descriptions of status {} failed, backfill stopped there, run it again to retry",
+                        status_id
+                    );
+                    if persist {
+                        state.save()?;
+                    }
+                    break 'pages;
+                }
+                state.cursor = Some(status_id);
+                if persist {
+                    state.save()?;
+                }
+            }
+        }
+        signal_listener.abort();
+        cache::flush();
+        Ok(())
+    }
+
+    /// Prints summary of configuration and processing state.
+    pub fn print_status(&self) {
+        let config = self.config.get();
+        let already_parsed = SHARED_DATA.lock().unwrap().already_parsed.len();
+        println!("Configuration file: {}", self.config.path().display());
+        println!("Mastodon instance:  {}", config.get_mastodon_base_url());
+        println!("Model:              {}", config.get_model());
+        println!(
+            "Streaming:          {}",
+            enabled_str(config.get_streaming_config().enabled)
+        );
+        println!(
+            "Manual refresh:     {}",
+            enabled_str(config.get_manual_refresh_config().enabled)
+        );
+        println!("Processed statuses: {}", already_parsed);
+        match QueueState::load() {
+            Ok(QueueState {
+                in_flight,
+                held,
+                updated: Some(updated),
+            }) => println!(
+                "Queue:              {} in flight, {} waiting for budget (as of {})",
+                in_flight,
+                held,
+                updated.format("%Y-%m-%d %H:%M:%S")
+            ),
+            Ok(_) => println!("Queue:              no run recorded"),
+            Err(err) => println!("Queue:              {}", err),
+        }
+        match BackfillState::load() {
+            Ok(backfill) => {
+                let described_today = if backfill.budget_day == Local::now().date_naive() {
+                    backfill.described_today
+                } else {
+                    0
+                };
+                println!(
+                    "Backfill:           {}, cursor: {}, described today: {}",
+                    if backfill.finished {
+                        "finished"
+                    } else {
+                        "not finished"
+                    },
+                    backfill.cursor.as_deref().unwrap_or("none"),
+                    described_today
+                );
+            }
+            Err(err) => println!("Backfill:           {}", err),
+        }
+        let (today, this_month, exceeded) = {
+            let usage = USAGE.lock().unwrap();
+            (
+                usage.today(),
+                usage.this_month(),
+                usage.exceeded_cap(&config.get_budget_config()),
+            )
+        };
+        println!("Spent today:        {}", usage_str(&today));
+        println!("Spent this month:   {}", usage_str(&this_month));
+        if let Some(exceeded) = exceeded {
+            println!("Paused:             {}", exceeded);
+        }
+        let (cached, stats) = {
+            let cache = DESCRIPTION_CACHE.lock().unwrap();
+            (cache.len(), cache.stats())
+        };
+        println!(
+            "Description cache:  {} ({} entries, {} hits, {} similar image hits, {} misses)",
+            enabled_str(config.get_cache_config().enabled),
+            cached,
+            stats.hits,
+            stats.near_hits,
+            stats.misses
+        );
+        if config.get_model_price().is_none() {
+            println!("No price configured for model, add it to gpt.prices to count spend");
+        }
+    }
+
+    /// Runs both loops until they finish or SIGINT/SIGTERM is received, then drains in-flight work.
+    pub async fn run(&self) -> Result<()> {
+        self.save_queue_state();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let config = self.config.clone();
+        let config_watch = tokio::spawn(async move {
+            config.watch().await;
+        });
+        let cancel = self.cancel.clone();
+        let cache_flush = tokio::spawn(async move {
+            while wait_or_cancel(&cancel, CACHE_FLUSH_INTERVAL).await {
+                cache::flush();
+            }
+        });
+        // keeps running during shutdown, so draining of in-flight work is visible too
+        let queue_save = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(QUEUE_SAVE_INTERVAL).await;
+                if self_clone3.queue_changed.swap(false, Ordering::Relaxed) {
+                    self_clone3.save_queue_state();
+                }
+            }
+        });
+        self.tracker.spawn(async move {
             self_clone
                 .streaming_loop()
                 .unwrap_or_else(|err| {
@@ -215,25 +917,58 @@ impl Handler {
                 })
                 .await;
         });
-        let manual_loop = tokio::spawn(async move {
-            std::thread::sleep(Duration::from_secs(10));
+        self.tracker.spawn(async move {
+            if !self_clone2.wait(Duration::from_secs(10)).await {
+                return;
+            }
             self_clone2
                 .manual_loop()
                 .unwrap_or_else(|err| {
-                    error!("Critical error in streaming loop\n{:#?}", err);
-                    panic!("{:#?}", err);
+                    error!("Critical error in manual loop\n{:#?}", err);
                 })
                 .await;
         });
-        let _ = tokio::join!(streaming_loop, manual_loop);
-        Ok(())
+        // tasks can still be spawned, wait() just completes once all of them are done
+        self.tracker.close();
+        let result = tokio::select! {
+            _ = self.tracker.wait() => Ok(()),
+            signal = shutdown_signal() => {
+                info!("{} received, finishing in-flight work", signal);
+                self.shutdown().await
+            }
+        };
+        config_watch.abort();
+        cache_flush.abort();
+        queue_save.abort();
+        // waits for the task, so it cannot save stale queue after the final one
+        let _ = queue_save.await;
+        self.save_queue_state();
+        if let Err(err) = SHARED_DATA.lock().unwrap().save() {
+            error!("{}", err);
+        }
+        cache::flush();
+        result
     }
 
-    #[allow(unreachable_code)]
-    pub async fn manual_loop(&self) -> Result<(), Box<dyn Error>> {
+    /// Stops accepting new updates and waits for in-flight ones up to configured deadline.
+    async fn shutdown(&self) -> Result<()> {
+        self.cancel.cancel();
+        let timeout = Duration::from_secs(self.config.get().get_shutdown_timeout());
+        match tokio::time::timeout(timeout, self.tracker.wait()).await {
+            Ok(()) => {
+                info!("All in-flight work finished");
+                Ok(())
+            }
+            Err(_) => {
+                self.abort.cancel();
+                Err(Error::ShutdownTimeout(timeout))
+            }
+        }
+    }
+
+    pub async fn manual_loop(&self) -> Result<()> {
         log::info!("Manual loop started");
-        log::debug!("Loading config");
-        let config = Config::from_json();
+        let config = self.config.get();
         let manual = config.get_manual_refresh_config();
         if !manual.enabled {
             log::info!("Manual refresh disabled, skipping");
@@ -248,8 +983,12 @@ impl Handler {
         log::debug!("Logged in as user id: {}", you.id);
 
         let mut initial = true;
-        std::thread::sleep(Duration::from_secs(manual.initial_delay));
+        if !self.wait(Duration::from_secs(manual.initial_delay)).await {
+            return Ok(());
+        }
         loop {
+            // pick up changes from reloaded configuration
+            let manual = self.config.get().get_manual_refresh_config();
             log::info!("Manually refreshing statuses");
             let mut request = StatusesRequest::new();
             request.only_media();
@@ -259,24 +998,31 @@ impl Handler {
                 manual.statuses
             };
             request.limit(statuses);
-            let statuses = mastodon.statuses(&you.id, request).await?;
-            let iter = statuses.items_iter();
-            iter.for_each(|status| async move {
-                self.handle_update(status.clone(), user_id.clone()).await;
-                std::thread::sleep(Duration::from_secs(1));
-            })
-            .await;
-            std::thread::sleep(Duration::from_secs(manual.interval));
-            initial = false;
+            match mastodon.statuses(&you.id, request).await {
+                Ok(statuses) => {
+                    initial = false;
+                    let iter = statuses.items_iter();
+                    tokio::pin!(iter);
+                    while let Some(status) = iter.next().await {
+                        self.handle_update(status, user_id.clone()).await;
+                        if !self.wait(Duration::from_secs(1)).await {
+                            break;
+                        }
+                    }
+                }
+                // transient errors must not stop refreshing, next interval tries again
+                Err(err) => error!("Cannot fetch statuses for manual refresh: {}", err),
+            }
+            if !self.wait(Duration::from_secs(manual.interval)).await {
+                log::info!("Manual loop stopped");
+                return Ok(());
+            }
         }
-        Ok(())
     }
 
-    #[allow(unreachable_code)]
-    pub async fn streaming_loop(&self) -> Result<(), Box<dyn Error>> {
+    pub async fn streaming_loop(&self) -> Result<()> {
         log::info!("Streaming loop started");
-        log::debug!("Loading config");
-        let config = Config::from_json();
+        let config = self.config.get();
         let data = config.to_mastodon_data();
         let mastodon = Mastodon::from(data);
         let streaming = config.get_streaming_config();
@@ -289,30 +1035,523 @@ impl Handler {
         let user_id = &format!("{}", &you.id);
         log::info!("Logged in");
         log::debug!("Logged in as user id: {}", you.id);
+        let mp = MastodonPatch::new(config.clone()).with_cancellation(self.cancel.clone());
+        // newest own status we know about, statuses after it are fetched after reconnect
+        let last_seen = match mp
+            .get_media_statuses_of_account(user_id.clone(), None, None, 1)
+            .await
+        {
+            Ok(page) => page.first().and_then(status_id_of),
+            Err(err) => {
+                // only gap recovery depends on it, streaming itself can start anyway
+                warn!("Cannot get newest own status: {}", err);
+                None
+            }
+        };
+        let last_seen = &Mutex::new(last_seen);
+        let failures = &AtomicU32::new(0);
         let mut counter = 0_u64;
 
-        loop {
+        while !self.cancel.is_cancelled() {
             counter += 1;
             debug!("Waiting for mastodon events (try: {})", counter);
-            let stream = mastodon.stream_user().await?;
-            stream
-                .try_for_each(|(event, _client)| async move {
-                    let user_id = user_id.clone();
-                    debug!("Event received:\n{:#?}", &event);
-                    if let Event::Update(update) = event {
-                        let self_clone = self.clone();
-                        let update_clone = update.clone();
-                        let user_id_clone = user_id.clone();
-                        tokio::spawn(async move {
-                            self_clone.handle_update(update_clone, user_id_clone).await;
-                        });
+            match mastodon.stream_user().await {
+                Ok(stream) => {
+                    // connected before fetching missed statuses, so nothing falls in between
+                    if counter > 1 {
+                        self.recover_gap(&mp, user_id, last_seen).await;
                     }
-                    Ok(())
-                })
-                .unwrap_or_else(|e| error!("Ignoring error while streaming: \n{:#?}", e))
-                .await;
+                    let events = stream
+                        .try_for_each(|(event, _client)| async move {
+                            failures.store(0, Ordering::Relaxed);
+                            debug!("Event received:\n{:#?}", &event);
+                            if let Event::Update(update) = event {
+                                if format!("{}", update.account.id) == *user_id {
+                                    *last_seen.lock().unwrap() = Some(update.id.to_string());
+                                }
+                                self.spawn_update(update, user_id.clone());
+                            }
+                            Ok(())
+                        })
+                        .unwrap_or_else(|e| error!("Error while streaming: \n{:#?}", e));
+                    tokio::select! {
+                        _ = events => {}
+                        _ = self.cancel.cancelled() => break,
+                    }
+                }
+                Err(err) => error!("Cannot connect to streaming API: \n{:#?}", err),
+            }
+            let max_delay = self.config.get().get_streaming_config().max_reconnect_delay;
+            let attempt = failures.fetch_add(1, Ordering::Relaxed);
+            let delay = Duration::from_secs((1_u64 << attempt.min(16)).min(max_delay.max(1)));
+            warn!(
+                "Stream disconnected, reconnecting in {} seconds",
+                delay.as_secs()
+            );
+            if !self.wait(delay).await {
+                break;
+            }
         }
-        // This will never be reached
+        log::info!("Streaming loop stopped");
         Ok(())
     }
+
+    fn spawn_update(&self, update: Status, user_id: String) {
+        let self_clone = self.clone();
+        self.tracker.spawn(async move {
+            self_clone.handle_update(update, user_id).await;
+        });
+    }
+
+    /// Handles own statuses with media posted after `last_seen`, missed while stream was disconnected.
+    async fn recover_gap(
+        &self,
+        mp: &MastodonPatch,
+        user_id: &str,
+        last_seen: &Mutex<Option<String>>,
+    ) {
+        let Some(mut min_id) = last_seen.lock().unwrap().clone() else {
+            return;
+        };
+        loop {
+            let page = match mp
+                .get_media_statuses_of_account(
+                    user_id.to_string(),
+                    None,
+                    Some(min_id.clone()),
+                    BACKFILL_PAGE_SIZE,
+                )
+                .await
+            {
+                Ok(page) => page,
+                Err(err) => {
+                    error!("Cannot fetch statuses missed while disconnected: {}", err);
+                    return;
+                }
+            };
+            if page.is_empty() {
+                return;
+            }
+            info!(
+                "Recovering {} statuses posted while disconnected",
+                page.len()
+            );
+            for value in page {
+                let Some(status_id) = status_id_of(&value) else {
+                    continue;
+                };
+                if newer_status_id(&status_id, &min_id) {
+                    min_id = status_id;
+                }
+                match serde_json::from_value::<Status>(value) {
+                    Ok(status) => self.spawn_update(status, user_id.to_string()),
+                    Err(err) => error!("Cannot parse missed status: {}", err),
+                }
+            }
+            *last_seen.lock().unwrap() = Some(min_id.clone());
+        }
+    }
+}
+
+fn status_id_of(status: &serde_json::Value) -> Option<String> {
+    status
+        .get("id")
+        .and_then(|id| id.as_str())
+        .map(|id| id.to_string())
+}
+
+// status IDs are numeric strings of growing length, so compare them as numbers
+fn newer_status_id(id: &str, than: &str) -> bool {
+    (id.len(), id) > (than.len(), than)
+}
+
+/// Images without description which can be described, as pairs of attachment ID and URL.
+fn images_to_describe(status: &Status) -> Vec<(String, String)> {
+    status
+        .media_attachments
+        .iter()
+        .filter(|attachment| {
+            attachment.media_type == MediaType::Image
+                && attachment
+                    .description
+                    .as_deref()
+                    .unwrap_or_default()
+                    .is_empty()
+        })
+        .filter_map(|attachment| Some((attachment.id.to_string(), attachment.url.clone()?)))
+        .collect()
+}
+
+/// Refusals and rejected descriptions would fail the same way again.
+fn is_permanent(err: &Error) -> bool {
+    matches!(err, Error::ContentPolicy(_) | Error::LowQuality(_))
+}
+
+/// Attachment being described, downloaded and recognized once for all languages.
+struct Image {
+    attachment_id: String,
+    url: String,
+    /// Downloaded only if description cache or OCR needs it.
+    bytes: Option<Arc<[u8]>>,
+    /// Text recognized by OCR in text-heavy image.
+    transcription: Option<ocr::Transcription>,
+}
+
+impl Image {
+    async fn prepare(config: &Config, attachment_id: String, url: String) -> Self {
+        let ocr_config = config.get_ocr_config();
+        let bytes = if config.get_cache_config().enabled || ocr_config.enabled {
+            match Vision::download_image(&url).await {
+                Ok(bytes) => Some(bytes),
+                Err(err) => {
+                    warn!("Cannot download attachment {}: {}", attachment_id, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let transcription = match bytes.as_ref().filter(|_| ocr_config.enabled) {
+            Some(bytes) => match ocr::transcribe(bytes, &ocr_config).await {
+                Ok(transcription) if transcription.is_text_heavy(&ocr_config) => {
+                    Some(transcription)
+                }
+                Ok(_) => None,
+                Err(err) => {
+                    warn!("OCR of attachment {} failed: {}", attachment_id, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        Self {
+            attachment_id,
+            url,
+            bytes,
+            transcription,
+        }
+    }
+}
+
+/// Generates description of single image in given language, reusing cached one and retrying transient errors.
+async fn describe_image(
+    config: Arc<Config>,
+    limiter: Arc<VisionLimiter>,
+    abort: &CancellationToken,
+    image: &Image,
+    lang: String,
+    context: String,
+) -> Result<Description> {
+    let attachment_id = &image.attachment_id;
+    let url = &image.url;
+    let cache_config = config.get_cache_config();
+    let ocr_config = config.get_ocr_config();
+    let cache_key = match image.bytes.clone().filter(|_| cache_config.enabled) {
+        Some(bytes) => match cache::lookup(bytes, &lang, &cache_config).await {
+            Ok((_, Some(description))) => {
+                info!(
+                    "Reused cached description for attachment {}: {}",
+                    attachment_id, description.alt_text
+                );
+                return Ok(description);
+            }
+            Ok((key, None)) => Some(key),
+            Err(err) => {
+                warn!(
+                    "Cannot look up attachment {} in description cache: {}",
+                    attachment_id, err
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let mut retry: u64 = 0;
+    loop {
+        retry += 1;
+        debug!(
+            "Generating {} description for attachment {} with URL: {}",
+            lang, attachment_id, url
+        );
+        debug!("Retry: {}", retry);
+        let context_transcription = image
+            .transcription
+            .as_ref()
+            .filter(|_| ocr_config.mode == OcrMode::Context)
+            .map(|transcription| transcription.text.clone());
+        let result = Vision::new(config.clone())
+            .with_limiter(limiter.clone())
+            .with_transcription(context_transcription)
+            .get_description(url.clone(), lang.clone(), context.clone())
+            .await;
+        match result {
+            Ok(description) => {
+                let mut description = description;
+                // OCR is more reliable in reading text than the model, in transcribe mode
+                // the text is appended to alt text after languages are combined
+                if let Some(transcription) = &image.transcription {
+                    description.detected_text = transcription.text.clone();
+                }
+                info!(
+                    "Generated description for attachment {}: {}",
+                    attachment_id, description.alt_text
+                );
+                if let Some(key) = &cache_key {
+                    cache::store(key, &lang, description.clone());
+                }
+                return Ok(description);
+            }
+            Err(err) => {
+                error!(
+                    "Failed to generate description for attachment {}: {:#?}",
+                    attachment_id, err
+                );
+                if retry >= 10 {
+                    error!("Maximum retry count reached, giving up");
+                    return Err(err);
+                }
+                // rejected requests would fail the same way again
+                if is_permanent(&err)
+                    || (matches!(err, Error::ProviderApi { .. }) && !err.is_retryable())
+                {
+                    error!("Error is permanent, giving up");
+                    return Err(err);
+                }
+                error!("Retrying after slight delay");
+                if !wait_or_cancel(abort, Duration::from_millis(2000)).await {
+                    return Err(Error::Cancelled);
+                }
+            }
+        };
+    }
+}
+
+/// Configured topics suggested by the model for any of the images, in order of configuration.
+fn content_warning_topics<'a>(
+    descriptions: impl Iterator<Item = &'a Description>,
+    topics: &[String],
+) -> Vec<String> {
+    let suggested: Vec<String> = descriptions
+        .flat_map(|description| description.content_warnings.iter())
+        .map(|suggested| suggested.trim().to_lowercase())
+        .filter(|suggested| !suggested.is_empty())
+        .collect();
+    topics
+        .iter()
+        .filter(|topic| {
+            let topic = topic.to_lowercase();
+            suggested
+                .iter()
+                .any(|suggested| suggested.contains(&topic) || topic.contains(suggested.as_str()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Logs parts of structured description which are not published as alt text.
+fn log_details(attachment_id: &str, description: &Description) {
+    if !description.long_description.is_empty() {
+        info!(
+            "Long description of attachment {}: {}",
+            attachment_id, description.long_description
+        );
+    }
+    if !description.detected_text.is_empty() {
+        info!(
+            "Text in attachment {}: {}",
+            attachment_id, description.detected_text
+        );
+    }
+    if !description.hashtags.is_empty() {
+        info!(
+            "Suggested hashtags for attachment {}: {}",
+            attachment_id,
+            description
+                .hashtags
+                .iter()
+                .map(|hashtag| format!("#{}", hashtag.trim_start_matches('#')))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    if !description.content_warnings.is_empty() {
+        info!(
+            "Suggested content warnings for attachment {}: {}",
+            attachment_id,
+            description.content_warnings.join(", ")
+        );
+    }
+}
+
+/// Joins alt texts in several languages, other parts are taken from description in the first one.
+fn combine_languages(mut descriptions: Vec<(String, Description)>, separator: &str) -> Description {
+    let alt_texts: Vec<_> = descriptions
+        .iter()
+        .map(|(lang, description)| (lang.clone(), description.alt_text.clone()))
+        .collect();
+    let alt_text = language::combine(&alt_texts, separator);
+    let (_, description) = descriptions.swap_remove(0);
+    Description {
+        alt_text,
+        ..description
+    }
+}
+
+/// Appends text recognized in the image to its description, shortened to fit Mastodon limit.
+/// Nothing is appended if there is no room left for it.
+fn append_transcription(description: &str, transcription: &str, labeled: bool) -> String {
+    let separator = if labeled {
+        "\n\nText in image: "
+    } else {
+        "\n\n"
+    };
+    let prefix = format!("{}{}", description, separator);
+    let available = MAX_DESCRIPTION_LENGTH.saturating_sub(prefix.chars().count());
+    if available < 2 {
+        return description.to_string();
+    }
+    format!("{}{}", prefix, shorten(transcription, available))
+}
+
+/// Languages in which the status is described: only its own language unless multilingual
+/// descriptions are enabled. Languages detected from hashtags and paragraphs of the status are
+/// preferred when there are at least two of them, configured list is used otherwise.
+fn description_languages(update: &Status, lang: &str, config: &MultilingualConfig) -> Vec<String> {
+    if !config.enabled {
+        return vec![lang.to_string()];
+    }
+    let mut languages = Vec::new();
+    if config.detect {
+        for tag in &update.tags {
+            if let Some(tag_lang) = config
+                .language_of_hashtag(&tag.name)
+                .and_then(language::normalize)
+            {
+                languages.push(tag_lang);
+            }
+        }
+        let text = update.content.replace("</p>", "\n").replace("<br />", "\n");
+        languages.extend(language::detect_all(&strip_tags(&text)));
+        let mut seen: Vec<String> = Vec::new();
+        languages.retain(|lang| {
+            let new = !seen.iter().any(|seen| language::same_language(seen, lang));
+            seen.push(lang.clone());
+            new
+        });
+    }
+    if languages.len() < 2 {
+        languages = config
+            .languages
+            .iter()
+            .filter_map(|lang| language::normalize(lang))
+            .collect();
+    }
+    if languages.is_empty() {
+        languages.push(lang.to_string());
+    }
+    languages
+}
+
+fn usage_str(total: &UsageTotal) -> String {
+    format!(
+        "${:.2} ({} requests, {} prompt and {} completion tokens)",
+        total.cost, total.requests, total.prompt_tokens, total.completion_tokens
+    )
+}
+
+fn enabled_str(enabled: bool) -> &'static str {
+    if enabled {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+/// Waits for given time, returns false if token was cancelled before it elapsed.
+pub async fn wait_or_cancel(cancel: &CancellationToken, duration: Duration) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => true,
+        _ = cancel.cancelled() => false,
+    }
+}
+
+/// Resolves when SIGINT or SIGTERM is received, returns name of the signal.
+pub async fn shutdown_signal() -> &'static str {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => Some(terminate),
+            Err(err) => {
+                warn!("Cannot listen for SIGTERM: {}", err);
+                None
+            }
+        };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        Some(_) = async {
+            match terminate.as_mut() {
+                Some(terminate) => terminate.recv().await,
+                None => std::future::pending::<Option<()>>().await,
+            }
+        } => "SIGTERM",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longer_status_id_is_newer() {
+        assert!(newer_status_id("112233445566778899", "99999999"));
+        assert!(!newer_status_id("99999999", "112233445566778899"));
+    }
+
+    #[test]
+    fn status_ids_of_same_length_compare_as_numbers() {
+        assert!(newer_status_id("110000000000000002", "110000000000000001"));
+        assert!(!newer_status_id("110000000000000001", "110000000000000002"));
+    }
+
+    #[test]
+    fn same_status_id_is_not_newer() {
+        assert!(!newer_status_id("110000000000000001", "110000000000000001"));
+    }
+
+    #[test]
+    fn status_id_is_read_from_json() {
+        assert_eq!(
+            status_id_of(&serde_json::json!({"id": "123"})).as_deref(),
+            Some("123")
+        );
+        assert_eq!(status_id_of(&serde_json::json!({"id": 123})), None);
+    }
+
+    #[test]
+    fn transcription_is_labeled_only_when_asked() {
+        assert_eq!(
+            append_transcription("A note", "Buy milk", true),
+            "A note\n\nText in image: Buy milk"
+        );
+        assert_eq!(
+            append_transcription("Notatka", "Kup mleko", false),
+            "Notatka\n\nKup mleko"
+        );
+    }
+
+    #[test]
+    fn transcription_is_shortened_to_fit_limit() {
+        let description = "a".repeat(MAX_DESCRIPTION_LENGTH - 10);
+        let alt_text = append_transcription(&description, "one two three four five", false);
+        assert!(alt_text.chars().count() <= MAX_DESCRIPTION_LENGTH);
+        assert!(alt_text.ends_with('…'));
+    }
+
+    #[test]
+    fn transcription_is_left_out_without_room() {
+        let description = "a".repeat(MAX_DESCRIPTION_LENGTH);
+        assert_eq!(
+            append_transcription(&description, "text", false),
+            description
+        );
+    }
 }
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::error::Result;
+use crate::shared_data::{load_json_state, save_json_state};
+
+const QUEUE_STATE_FILE: &str = "queue_state.json";
+
+/// Statuses which are currently being described, shared by streaming and manual loops
+/// so the same status is never sent to the vision API twice at the same time.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    claims: Mutex<HashMap<String, watch::Receiver<Option<usize>>>>,
+}
+
+pub enum Claim<'a> {
+    /// Caller is the only one handling the status until the guard is dropped.
+    Owned(ClaimGuard<'a>),
+    /// Someone else is handling the status, receiver gets number of descriptions added.
+    InProgress(watch::Receiver<Option<usize>>),
+}
+
+pub struct ClaimGuard<'a> {
+    registry: &'a InFlight,
+    id: String,
+    sender: watch::Sender<Option<usize>>,
+}
+
+impl ClaimGuard<'_> {
+    /// Publishes result to everyone waiting for this status.
+    pub fn finish(self, described: usize) {
+        let _ = self.sender.send(Some(described));
+    }
+}
+
+impl Drop for ClaimGuard<'_> {
+    fn drop(&mut self) {
+        self.registry.claims.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of statuses being described right now.
+    pub fn len(&self) -> usize {
+        self.claims.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn claim(&self, id: &str) -> Claim<'_> {
+        let mut claims = self.claims.lock().unwrap();
+        if let Some(receiver) = claims.get(id) {
+            return Claim::InProgress(receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(None);
+        claims.insert(id.to_string(), receiver);
+        Claim::Owned(ClaimGuard {
+            registry: self,
+            id: id.to_string(),
+            sender,
+        })
+    }
+}
+
+/// Queue of running process saved for `status` command, which runs as separate process.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueState {
+    /// Statuses being described.
+    pub in_flight: usize,
+    /// Statuses waiting for spending budget to reset.
+    pub held: usize,
+    pub updated: Option<DateTime<Local>>,
+}
+
+impl QueueState {
+    pub fn load() -> Result<Self> {
+        load_json_state(QUEUE_STATE_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json_state(QUEUE_STATE_FILE, self)
+    }
+}
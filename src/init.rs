@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+
+use log::{debug, info};
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::{default_model, is_placeholder, verify_openai, Config};
+use crate::error::{Error, Result};
+
+const APP_NAME: &str = "MastoVision";
+const SCOPES: &str = "read write";
+// out-of-band redirect makes Mastodon display the code instead of redirecting
+const REDIRECT_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
+
+#[derive(Debug, Deserialize)]
+struct RegisteredApp {
+    client_id: String,
+    client_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Account {
+    acct: String,
+}
+
+/// Interactive setup: registers application on instance, authorizes it and writes config file.
+pub struct Init {
+    path: PathBuf,
+    force: bool,
+    client: reqwest::Client,
+}
+
+impl Init {
+    pub fn new(path: impl Into<PathBuf>, force: bool) -> Self {
+        Self {
+            path: path.into(),
+            force,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn run(&self, instance: Option<String>) -> Result<()> {
+        if self.path.exists() && !self.force {
+            return Err(Error::InvalidInput(format!(
+                "{} already exists, use --force to overwrite it",
+                self.path.display()
+            )));
+        }
+        let instance = match instance {
+            Some(instance) => instance,
+            None => prompt("Mastodon instance (e.g. https://mastodon.social): ").await?,
+        };
+        let base_url = normalize_instance_url(&instance)?;
+        // checked before registering the app, so typo does not waste issued credentials
+        let gpt_access_token = loop {
+            let gpt_access_token = prompt("OpenAI API key: ").await?;
+            if is_placeholder(&gpt_access_token) {
+                println!("This doesn't look like an OpenAI API key, please try again");
+                continue;
+            }
+            match verify_openai(&default_model(), &gpt_access_token).await {
+                Ok(()) => break gpt_access_token,
+                Err(err) => println!("{}, please try again", err),
+            }
+        };
+
+        info!("Registering application on {}", &base_url);
+        let app = self.register_app(&base_url).await?;
+        debug!("Registered application with client id: {}", &app.client_id);
+
+        let authorize_url = Url::parse_with_params(
+            &format!("{}/oauth/authorize", &base_url),
+            &[
+                ("client_id", app.client_id.as_str()),
+                ("scope", SCOPES),
+                ("redirect_uri", REDIRECT_URI),
+                ("response_type", "code"),
+            ],
+        )
+        .map_err(|err| Error::InvalidInput(err.to_string()))?;
+        println!(
+            "Open following URL in your browser, authorize MastoVision and paste the code below:\n\n{}\n",
+            authorize_url
+        );
+        let code = prompt("Authorization code: ").await?;
+        let access_token = self.exchange_code(&base_url, &app, &code).await?;
+
+        let account = self.verify_credentials(&base_url, &access_token).await?;
+        info!("Authorized as @{}", account.acct);
+
+        let config = Config::new(
+            base_url,
+            app.client_id,
+            app.client_secret,
+            access_token,
+            gpt_access_token,
+        );
+        // saved before validation, so issued credentials are kept even if something needs fixing
+        config.save(&self.path)?;
+        info!("Configuration written to {}", self.path.display());
+        config.validate()?;
+        Ok(())
+    }
+
+    async fn register_app(&self, base_url: &str) -> Result<RegisteredApp> {
+        let response = self
+            .client
+            .post(format!("{}/api/v1/apps", base_url))
+            .form(&[
+                ("client_name", APP_NAME),
+                ("redirect_uris", REDIRECT_URI),
+                ("scopes", SCOPES),
+            ])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::MastodonApi { status, body });
+        }
+        Ok(response.json().await?)
+    }
+
+    async fn exchange_code(
+        &self,
+        base_url: &str,
+        app: &RegisteredApp,
+        code: &str,
+    ) -> Result<String> {
+        let response = self
+            .client
+            .post(format!("{}/oauth/token", base_url))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("client_id", app.client_id.as_str()),
+                ("client_secret", app.client_secret.as_str()),
+                ("redirect_uri", REDIRECT_URI),
+                ("scope", SCOPES),
+            ])
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::MastodonApi { status, body });
+        }
+        let token: Token = response.json().await?;
+        Ok(token.access_token)
+    }
+
+    async fn verify_credentials(&self, base_url: &str, access_token: &str) -> Result<Account> {
+        let response = self
+            .client
+            .get(format!("{}/api/v1/accounts/verify_credentials", base_url))
+            .header("Authorization", format!("Bearer {}", access_token))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(Error::MastodonApi { status, body });
+        }
+        Ok(response.json().await?)
+    }
+}
+
+// accepts bare domain as well as full URL, returns URL without trailing slash
+fn normalize_instance_url(instance: &str) -> Result<String> {
+    let instance = instance.trim();
+    let instance = if instance.contains("://") {
+        instance.to_string()
+    } else {
+        format!("https://{}", instance)
+    };
+    let url = Url::parse(&instance).map_err(|err| {
+        Error::InvalidInput(format!("'{}' is not a valid URL ({})", instance, err))
+    })?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::InvalidInput("Instance URL has no host".to_string()))?;
+    let port = url
+        .port()
+        .map(|port| format!(":{}", port))
+        .unwrap_or_default();
+    Ok(format!("{}://{}{}", url.scheme(), host, port))
+}
+
+async fn prompt(message: &str) -> Result<String> {
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(message.as_bytes()).await?;
+    stdout.flush().await?;
+    // std stdin is buffered globally, so consecutive prompts don't lose piped input
+    let line = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).map(|_| line)
+    })
+    .await
+    .map_err(|err| Error::Io(std::io::Error::other(err)))??;
+    let line = line.trim().to_string();
+    if line.is_empty() {
+        return Err(Error::InvalidInput("No value provided".to_string()));
+    }
+    Ok(line)
+}
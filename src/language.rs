@@ -0,0 +1,345 @@
+use whatlang::Detector;
+
+// shorter texts are detected too unreliably to act on
+const MIN_DETECTION_LENGTH: usize = 20;
+// Mastodon rejects media descriptions longer than this
+pub(crate) const MAX_DESCRIPTION_LENGTH: usize = 1500;
+
+/// Normalizes BCP-47 tag or ISO 639 code to form used in prompts, e.g. `EN_us` -> `en-US`, `deu` -> `de`.
+/// Returns `None` for tags without usable language, like `und`.
+pub fn normalize(tag: &str) -> Option<String> {
+    let mut subtags = tag.trim().split(['-', '_']);
+    let primary = subtags.next()?.to_ascii_lowercase();
+    if !(2..=3).contains(&primary.len()) || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let primary = match primary.as_str() {
+        "und" | "mul" | "zxx" => return None,
+        code if code.len() == 3 => iso_639_1(code)
+            .or_else(|| bibliographic_iso_639_1(code))
+            .map(|code| code.to_string())
+            .unwrap_or(primary),
+        _ => primary,
+    };
+    let mut normalized = primary;
+    for subtag in subtags {
+        let is_script = subtag.len() == 4 && subtag.chars().all(|c| c.is_ascii_alphabetic());
+        let is_region = (subtag.len() == 2 && subtag.chars().all(|c| c.is_ascii_alphabetic()))
+            || (subtag.len() == 3 && subtag.chars().all(|c| c.is_ascii_digit()));
+        if is_script {
+            let mut chars = subtag.chars();
+            normalized.push('-');
+            normalized.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+            normalized.push_str(&chars.as_str().to_ascii_lowercase());
+        } else if is_region {
+            normalized.push('-');
+            normalized.push_str(&subtag.to_ascii_uppercase());
+        } else {
+            // variants and extensions don't matter for descriptions
+            break;
+        }
+    }
+    Some(normalized)
+}
+
+/// Picks language of description: language of the status if set, then detected language of its text,
+/// then the default.
+pub fn resolve(status_language: Option<&str>, text: &str, default: &str) -> String {
+    status_language
+        .and_then(normalize)
+        .or_else(|| detect(text))
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// Detects language of text, returns two letter ISO 639-1 code if detection is reliable.
+pub fn detect(text: &str) -> Option<String> {
+    if text.chars().filter(|c| c.is_alphabetic()).count() < MIN_DETECTION_LENGTH {
+        return None;
+    }
+    let info = Detector::new().detect(text)?;
+    if !info.is_reliable() {
+        return None;
+    }
+    iso_639_1(info.lang().code()).map(|code| code.to_string())
+}
+
+/// Detects languages of separate lines of text, in order of first appearance.
+pub fn detect_all(text: &str) -> Vec<String> {
+    let mut languages: Vec<String> = Vec::new();
+    for detected in text.lines().filter_map(detect) {
+        if !languages
+            .iter()
+            .any(|language| same_language(language, &detected))
+        {
+            languages.push(detected);
+        }
+    }
+    languages
+}
+
+/// Joins descriptions in several languages into one alt text, e.g. "EN: A cat / PL: Kot",
+/// shortening each of them equally to fit Mastodon limit. Single description is returned as is.
+pub fn combine(descriptions: &[(String, String)], separator: &str) -> String {
+    if let [(_, description)] = descriptions {
+        return description.clone();
+    }
+    let labels: Vec<String> = descriptions
+        .iter()
+        .map(|(language, _)| format!("{}: ", language.to_uppercase()))
+        .collect();
+    let overhead = labels
+        .iter()
+        .map(|label| label.chars().count())
+        .sum::<usize>()
+        + separator.chars().count() * descriptions.len().saturating_sub(1);
+    let available = MAX_DESCRIPTION_LENGTH.saturating_sub(overhead) / descriptions.len().max(1);
+    labels
+        .iter()
+        .zip(descriptions)
+        .map(|(label, (_, description))| format!("{}{}", label, shorten(description, available)))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+// cuts text at word boundary so it has at most `max` characters including ellipsis
+pub(crate) fn shorten(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max.saturating_sub(1)).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(position) if position > 0 => &cut[..position],
+        _ => cut.as_str(),
+    };
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_whitespace() || c == ',' || c == '.')
+    )
+}
+
+/// Compares language codes by primary subtag, treating Norwegian variants as the same language.
+pub fn same_language(a: &str, b: &str) -> bool {
+    let primary = |code: &str| {
+        let code = code
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match code.as_str() {
+            "nb" | "nn" => "no".to_string(),
+            _ => code,
+        }
+    };
+    primary(a) == primary(b)
+}
+
+// ISO 639-2/B codes which differ from ISO 639-3 ones
+fn bibliographic_iso_639_1(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "alb" | "sqi" => "sq",
+        "arm" => "hy",
+        "baq" | "eus" => "eu",
+        "bur" => "my",
+        "chi" | "zho" => "zh",
+        "cze" => "cs",
+        "dut" => "nl",
+        "fre" => "fr",
+        "geo" => "ka",
+        "ger" => "de",
+        "gre" => "el",
+        "ice" | "isl" => "is",
+        "mac" => "mk",
+        "may" | "msa" => "ms",
+        "nno" => "nn",
+        "nor" => "no",
+        "per" | "fas" => "fa",
+        "rum" => "ro",
+        "slo" => "sk",
+        "tib" | "bod" => "bo",
+        "wel" | "cym" => "cy",
+        "gle" => "ga",
+        "glg" => "gl",
+        _ => return None,
+    })
+}
+
+// maps ISO 639-3 codes used by whatlang to ISO 639-1 codes used by Mastodon
+fn iso_639_1(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "afr" => "af",
+        "aka" => "ak",
+        "amh" => "am",
+        "ara" => "ar",
+        "aze" => "az",
+        "bel" => "be",
+        "ben" => "bn",
+        "bul" => "bg",
+        "cat" => "ca",
+        "ces" => "cs",
+        "cmn" => "zh",
+        "dan" => "da",
+        "deu" => "de",
+        "ell" => "el",
+        "eng" => "en",
+        "epo" => "eo",
+        "est" => "et",
+        "fin" => "fi",
+        "fra" => "fr",
+        "guj" => "gu",
+        "heb" => "he",
+        "hin" => "hi",
+        "hrv" => "hr",
+        "hun" => "hu",
+        "hye" => "hy",
+        "ind" => "id",
+        "ita" => "it",
+        "jav" => "jv",
+        "jpn" => "ja",
+        "kan" => "kn",
+        "kat" => "ka",
+        "khm" => "km",
+        "kor" => "ko",
+        "lat" => "la",
+        "lav" => "lv",
+        "lit" => "lt",
+        "mal" => "ml",
+        "mar" => "mr",
+        "mkd" => "mk",
+        "mya" => "my",
+        "nep" => "ne",
+        "nld" => "nl",
+        "nob" => "nb",
+        "ori" => "or",
+        "pan" => "pa",
+        "pes" => "fa",
+        "pol" => "pl",
+        "por" => "pt",
+        "ron" => "ro",
+        "rus" => "ru",
+        "sin" => "si",
+        "slk" => "sk",
+        "slv" => "sl",
+        "sna" => "sn",
+        "spa" => "es",
+        "srp" => "sr",
+        "swe" => "sv",
+        "tam" => "ta",
+        "tel" => "te",
+        "tgl" => "tl",
+        "tha" => "th",
+        "tuk" => "tk",
+        "tur" => "tr",
+        "ukr" => "uk",
+        "urd" => "ur",
+        "uzb" => "uz",
+        "vie" => "vi",
+        "yid" => "yi",
+        "zul" => "zu",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_fixes_case_and_separator() {
+        assert_eq!(normalize("EN_us").as_deref(), Some("en-US"));
+        assert_eq!(normalize(" pt-br ").as_deref(), Some("pt-BR"));
+        assert_eq!(normalize("es-419").as_deref(), Some("es-419"));
+    }
+
+    #[test]
+    fn normalize_maps_three_letter_codes() {
+        assert_eq!(normalize("deu").as_deref(), Some("de"));
+        assert_eq!(normalize("ger").as_deref(), Some("de"));
+        assert_eq!(normalize("fre").as_deref(), Some("fr"));
+        assert_eq!(normalize("POL").as_deref(), Some("pl"));
+        // codes without two letter equivalent are kept
+        assert_eq!(normalize("haw").as_deref(), Some("haw"));
+    }
+
+    #[test]
+    fn normalize_keeps_script_and_region() {
+        assert_eq!(normalize("zh-hant-tw").as_deref(), Some("zh-Hant-TW"));
+        assert_eq!(normalize("sr_LATN").as_deref(), Some("sr-Latn"));
+    }
+
+    #[test]
+    fn normalize_drops_variants_and_extensions() {
+        assert_eq!(normalize("en-US-x-twain").as_deref(), Some("en-US"));
+        assert_eq!(normalize("de-1996").as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn normalize_rejects_unusable_tags() {
+        for tag in ["", "und", "mul", "zxx", "e", "english", "12", "e1"] {
+            assert_eq!(normalize(tag), None, "{}", tag);
+        }
+    }
+
+    #[test]
+    fn resolve_prefers_status_language() {
+        assert_eq!(resolve(Some("pl"), "", "en"), "pl");
+        assert_eq!(resolve(Some("und"), "", "en"), "en");
+        assert_eq!(resolve(None, "short", "de"), "de");
+    }
+
+    #[test]
+    fn same_language_compares_primary_subtag() {
+        assert!(same_language("en", "en-GB"));
+        assert!(same_language("pt_BR", "PT"));
+        assert!(same_language("nb", "no"));
+        assert!(same_language("nn-NO", "nb"));
+        assert!(!same_language("en", "de"));
+    }
+
+    #[test]
+    fn shorten_keeps_short_text() {
+        assert_eq!(shorten("A cat", 5), "A cat");
+        assert_eq!(shorten("", 0), "");
+    }
+
+    #[test]
+    fn shorten_cuts_at_word_boundary() {
+        assert_eq!(shorten("A cat sleeps on a sofa", 12), "A cat…");
+        assert_eq!(shorten("A cat, sleeping", 9), "A cat…");
+    }
+
+    #[test]
+    fn shorten_counts_characters_not_bytes() {
+        assert_eq!(shorten("Zażółć gęślą jaźń", 10), "Zażółć…");
+        assert_eq!(shorten("żółwżółw", 4), "żół…");
+        assert_eq!(shorten("猫が寝ている", 3), "猫が…");
+    }
+
+    #[test]
+    fn combine_returns_single_description_unchanged() {
+        let descriptions = [("en".to_string(), "A cat".to_string())];
+        assert_eq!(combine(&descriptions, " / "), "A cat");
+    }
+
+    #[test]
+    fn combine_labels_languages() {
+        let descriptions = [
+            ("en".to_string(), "A cat".to_string()),
+            ("pl".to_string(), "Kot".to_string()),
+        ];
+        assert_eq!(combine(&descriptions, " / "), "EN: A cat / PL: Kot");
+    }
+
+    #[test]
+    fn combine_fits_mastodon_limit() {
+        let long = "słowo ".repeat(400);
+        let descriptions = [
+            ("en".to_string(), long.clone()),
+            ("pl".to_string(), long.clone()),
+            ("de".to_string(), long),
+        ];
+        let combined = combine(&descriptions, " / ");
+        assert!(combined.chars().count() <= MAX_DESCRIPTION_LENGTH);
+        assert!(combined.starts_with("EN: słowo"));
+        assert_eq!(combined.matches('…').count(), 3);
+    }
+}
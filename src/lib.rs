@@ -1,5 +1,18 @@
+pub mod backfill;
+pub mod cache;
+pub mod cli;
 pub mod config;
+pub mod error;
 pub mod handler;
+pub mod in_flight;
+pub mod init;
+pub mod language;
 pub mod mastodon_patch;
+pub mod ocr;
+pub mod quality;
+pub mod rate_limit;
+pub mod report;
 pub mod shared_data;
+pub mod thread;
+pub mod usage;
 pub mod vision;
@@ -1,13 +1,127 @@
 use log::*;
+use std::sync::Arc;
 
 use kv_log_macro as log;
+use masto_vision::cli;
+use masto_vision::config::{Config, SharedConfig};
+use masto_vision::error::Error;
 use masto_vision::handler::Handler;
+use masto_vision::init::Init;
+use masto_vision::language;
+use masto_vision::report::DryRunReport;
+use masto_vision::vision::Vision;
+const EXIT_ERROR: i32 = 1;
+// in-flight work was abandoned because it didn't finish before shutdown deadline
+const EXIT_SHUTDOWN_TIMEOUT: i32 = 2;
+
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
-    let handler = Handler {};
-    let _ = handler.setup_logging();
-    info!("Starting MastoVision!");
-    handler.run().await.unwrap_or_else(|err| {
-        error!("Critical error\n{:#?}", err);
+    let matches = cli::command().get_matches();
+    let _ = Handler::setup_logging(cli::get_log_level(&matches));
+    let config_path = cli::get_config_path(&matches);
+
+    // subcommands which don't need valid configuration
+    match matches.subcommand() {
+        Some(("init", init)) => {
+            let instance = init.get_one::<String>("instance").cloned();
+            if let Err(err) = Init::new(&config_path, init.get_flag("force"))
+                .run(instance)
+                .await
+            {
+                error!("Initialization failed: {}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        Some(("config", config)) => {
+            if config.subcommand_matches("schema").is_some() {
+                println!("{}", Config::json_schema());
+            }
+            return;
+        }
+        Some(("check-config", _)) => {
+            if let Err(err) = Handler::check_config(&config_path).await {
+                error!("{}", err);
+                std::process::exit(EXIT_ERROR);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let config = match matches.subcommand() {
+        Some(("describe", _)) => cli::load_config_without_mastodon(&config_path),
+        _ => cli::load_config(&config_path),
+    };
+    let config = config.unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(EXIT_ERROR);
     });
+    if let Err(err) = Handler::check_state() {
+        error!("{}, fix or move the file away to start", err);
+        std::process::exit(EXIT_ERROR);
+    }
+    let shared_config = SharedConfig::new(&config_path, config);
+    let mut handler = Handler::new(shared_config.clone());
+    if let Some(report_path) = cli::get_dry_run_report_path(&matches) {
+        match DryRunReport::open(&report_path) {
+            Ok(report) => {
+                info!(
+                    "Dry run enabled, statuses won't be edited, see report: {}",
+                    report_path
+                );
+                handler = handler.with_dry_run(Arc::new(report));
+            }
+            Err(err) => {
+                error!("Cannot open dry-run report {}: {}", report_path, err);
+                std::process::exit(EXIT_ERROR);
+            }
+        }
+    }
+    let result = match matches.subcommand() {
+        Some(("describe", describe)) => {
+            let source = describe.get_one::<String>("source").unwrap();
+            let lang = match describe.get_one::<String>("language") {
+                Some(lang) => language::normalize(lang).unwrap_or_else(|| {
+                    error!("'{}' is not a valid language code", lang);
+                    std::process::exit(EXIT_ERROR);
+                }),
+                None => shared_config.get().get_default_language(),
+            };
+            let context = describe.get_one::<String>("context").unwrap();
+            match Vision::image_url_from_source(source).await {
+                Ok(url) => Vision::new(shared_config.get())
+                    .get_description(url, lang, context.clone())
+                    .await
+                    .map(|description| {
+                        println!("{}", description.alt_text);
+                        if shared_config.get().get_structured_output() {
+                            println!("{:#}", serde_json::json!(description));
+                        }
+                    }),
+                Err(err) => Err(err),
+            }
+        }
+        Some(("process", process)) => {
+            let status = process.get_one::<String>("status").unwrap();
+            handler.process_status(status).await
+        }
+        Some(("backfill", backfill)) => handler.backfill(cli::get_backfill_options(backfill)).await,
+        Some(("status", _)) => {
+            handler.print_status();
+            Ok(())
+        }
+        _ => {
+            info!("Starting MastoVision!");
+            handler.run().await
+        }
+    };
+    if let Err(err) = result {
+        if let Error::ShutdownTimeout(_) = err {
+            error!("{}", err);
+            std::process::exit(EXIT_SHUTDOWN_TIMEOUT);
+        }
+        error!("Critical error\n{:#?}", err);
+        std::process::exit(EXIT_ERROR);
+    }
 }
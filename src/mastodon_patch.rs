@@ -1,18 +1,87 @@
-use log::debug;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use serde_json::json;
-use std::time::Duration;
-use std::{collections::HashMap, error::Error};
+use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
 use voca_rs::strip::strip_tags;
 
+use crate::error::{Error, Result};
+use crate::handler::wait_or_cancel;
+use crate::report::{DryRunReport, ReportEntry};
+
+// when fewer requests than this are left, wait for the rate limit window to reset
+const LOW_RATE_LIMIT_REMAINING: u64 = 5;
+
+/// Time until which no request to Mastodon is sent, shared by all instances.
+static PAUSED_UNTIL: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
 #[derive(Debug, Clone)]
 pub struct MastodonPatch {
-    config: crate::config::Config,
+    config: Arc<crate::config::Config>,
+    dry_run: Option<Arc<DryRunReport>>,
+    cancel: CancellationToken,
 }
 
 // implement methods which are currently not supported by MastodonAsync
 impl MastodonPatch {
-    pub fn new(config: crate::config::Config) -> Self {
-        Self { config }
+    pub fn new(config: Arc<crate::config::Config>) -> Self {
+        Self {
+            config,
+            dry_run: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Retries stop waiting as soon as the token is cancelled.
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// In dry-run mode status updates are written to the report instead of being sent.
+    pub fn with_dry_run(mut self, report: Option<Arc<DryRunReport>>) -> Self {
+        self.dry_run = report;
+        self
+    }
+
+    /// Sends request once rate limit allows it and records rate limit headers of the response.
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        loop {
+            let paused_until = *PAUSED_UNTIL.lock().unwrap();
+            let Some(remaining) =
+                paused_until.and_then(|until| until.checked_duration_since(Instant::now()))
+            else {
+                break;
+            };
+            info!(
+                "Mastodon rate limit reached, pausing requests for {} seconds",
+                remaining.as_secs()
+            );
+            if !wait_or_cancel(&self.cancel, remaining).await {
+                return Err(Error::Cancelled);
+            }
+        }
+        let response = request
+            .header(
+                "Authorization",
+                format!("Bearer {}", self.config.get_mastodon_access_token()),
+            )
+            .send()
+            .await?;
+        record_rate_limit(response.status(), response.headers());
+        if response.status().is_success() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        Err(Error::MastodonApi { status, body })
     }
 
     // this does not work despite mastodon API reference tells otherwise :(
@@ -20,7 +89,7 @@ impl MastodonPatch {
         &self,
         image_id: String,
         image_description: String,
-    ) -> Result<bool, Box<dyn Error>> {
+    ) -> Result<bool> {
         let client = reqwest::Client::new();
         let url = format!(
             "{}/api/v1/media/{}",
@@ -30,29 +99,25 @@ impl MastodonPatch {
         debug!("Trying to PUT new image description: {}", &url);
         let mut params = HashMap::new();
         params.insert("description", image_description.clone());
-        let response = client
-            .put(url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.get_mastodon_access_token()),
-            )
-            .json(&json!(
+        let response = self
+            .send(client.put(url).json(&json!(
                 {
                     "description": image_description
                 }
-            ))
-            .send()
-            .await?;
+            )))
+            .await;
         debug!("Response from API: {:#?}", &response);
-        let is_success = response.status().is_success();
-        debug!("Success response?: {}", is_success);
-        Ok(is_success)
+        match response {
+            Ok(_) => Ok(true),
+            Err(Error::MastodonApi { .. }) => {
+                debug!("Success response?: false");
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
     }
 
-    pub async fn get_json_of_message(
-        &self,
-        message_id: String,
-    ) -> Result<Option<String>, Box<dyn Error>> {
+    pub async fn get_json_of_message(&self, message_id: String) -> Result<String> {
         let client = reqwest::Client::new();
         let url = format!(
             "{}/api/v1/statuses/{}",
@@ -60,36 +125,75 @@ impl MastodonPatch {
             message_id
         );
         debug!("Trying to GET message: {}", &url);
-        let response = client
-            .get(url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.get_mastodon_access_token()),
-            )
-            .header("Content-Type", "application/json")
-            .send()
+        let response = self
+            .send(client.get(url).header("Content-Type", "application/json"))
             .await?;
-        if response.status().is_success() {
-            let body = response.text().await?;
-            debug!("Response from API: {:#?}", &body);
-            return Ok(Some(body));
+        let body = response.text().await?;
+        debug!("Response from API: {:#?}", &body);
+        Ok(body)
+    }
+
+    /// Returns ancestors and descendants of the status in the thread.
+    pub async fn get_context_of_message(&self, message_id: String) -> Result<serde_json::Value> {
+        let client = reqwest::Client::new();
+        let url = format!(
+            "{}/api/v1/statuses/{}/context",
+            self.config.get_mastodon_base_url(),
+            message_id
+        );
+        debug!("Trying to GET context of message: {}", &url);
+        let response = self.send(client.get(url)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Returns page of account statuses with media, newest first, older than `max_id`
+    /// and immediately newer than `min_id` if given.
+    pub async fn get_media_statuses_of_account(
+        &self,
+        account_id: String,
+        max_id: Option<String>,
+        min_id: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<serde_json::Value>> {
+        let client = reqwest::Client::new();
+        let url = format!(
+            "{}/api/v1/accounts/{}/statuses",
+            self.config.get_mastodon_base_url(),
+            account_id
+        );
+        let mut query = vec![
+            ("only_media", "true".to_string()),
+            ("limit", limit.to_string()),
+        ];
+        if let Some(max_id) = max_id {
+            query.push(("max_id", max_id));
         }
-        Ok(None)
+        if let Some(min_id) = min_id {
+            query.push(("min_id", min_id));
+        }
+        debug!("Trying to GET statuses: {} {:?}", &url, &query);
+        let response = self.send(client.get(url).query(&query)).await?;
+        Ok(response.json().await?)
     }
 
     pub async fn get_json_of_message_with_retry(
         &self,
         message_id: String,
         retries: u64,
-    ) -> Result<Option<String>, Box<dyn Error>> {
+    ) -> Result<String> {
         let mut retries = retries;
         loop {
             let result = self.get_json_of_message(message_id.clone()).await;
-            if result.is_ok() || retries == 0 {
-                return result;
+            match &result {
+                Err(err) if retries > 0 && err.is_retryable() => {
+                    warn!("Cannot get message {}, retrying: {}", message_id, err)
+                }
+                _ => return result,
             }
             retries -= 1;
-            std::thread::sleep(Duration::from_secs(5));
+            if !wait_or_cancel(&self.cancel, Duration::from_secs(5)).await {
+                return result;
+            }
         }
     }
 
@@ -98,15 +202,18 @@ impl MastodonPatch {
         json_string: String,
         message_id: String,
         image_id_with_description: HashMap<String, String>,
-    ) -> Result<(), Box<dyn Error>> {
+        content_warning: Option<String>,
+    ) -> Result<()> {
         let client = reqwest::Client::new();
         let url = format!(
             "{}/api/v1/statuses/{}",
             self.config.get_mastodon_base_url(),
             message_id
         );
-        let previous_json = serde_json::from_str::<serde_json::Value>(&json_string).unwrap();
-        let previous_json = previous_json.as_object().unwrap();
+        let previous_json = serde_json::from_str::<serde_json::Value>(&json_string)?;
+        let previous_json = previous_json
+            .as_object()
+            .ok_or_else(|| Error::invalid_response("Mastodon", "status is not a JSON object"))?;
         debug!("Previous json: {:#?}", &previous_json);
         let empty = &json!("");
         // don't ask me why you cannot get message in exact form it was posted but instead it returns html
@@ -125,50 +232,100 @@ impl MastodonPatch {
             .as_array()
             .cloned()
             .unwrap_or_default();
-        media_attachments.iter_mut().for_each(|attachment| {
-            let attachment = attachment.as_object_mut().unwrap();
-            let id = attachment.get("id").unwrap().as_str().unwrap().to_string();
-            if image_id_with_description.contains_key(&id) {
-                let description = image_id_with_description.get(&id).unwrap();
+        for attachment in media_attachments.iter_mut() {
+            let attachment = attachment.as_object_mut().ok_or_else(|| {
+                Error::invalid_response("Mastodon", "media attachment is not a JSON object")
+            })?;
+            let id = attachment
+                .get("id")
+                .and_then(|id| id.as_str())
+                .ok_or_else(|| Error::invalid_response("Mastodon", "media attachment without id"))?
+                .to_string();
+            let described = attachment
+                .get("description")
+                .and_then(|description| description.as_str())
+                .is_some_and(|description| !description.is_empty());
+            // description written by the author while generating wins
+            if let Some(description) = image_id_with_description.get(&id).filter(|_| !described) {
                 attachment.insert("description".to_string(), description.clone().into());
-                media_ids.push(id);
             }
-        });
+            // edit replaces media of the status, so attachments left out would be removed
+            media_ids.push(id);
+        }
 
+        // content warning written by the author is kept, media is only marked sensitive
+        let (spoiler_text, sensitive) = match content_warning {
+            Some(content_warning) => {
+                let spoiler_text = previous_json
+                    .get("spoiler_text")
+                    .and_then(|spoiler_text| spoiler_text.as_str())
+                    .filter(|spoiler_text| !spoiler_text.is_empty())
+                    .map(|spoiler_text| spoiler_text.to_string())
+                    .unwrap_or(content_warning);
+                (json!(spoiler_text), json!(true))
+            }
+            None => (
+                json!(previous_json.get("spoiler_text")),
+                json!(previous_json.get("sensitive")),
+            ),
+        };
         let patched_json = json!(
             {
                 "status": content,
                 "in_reply_to_id": previous_json.get("in_reply_to_id"),
                 "media_ids": media_ids,
                 "media_attributes": media_attachments,
-                "sensitive": previous_json.get("sensitive"),
-                "spoiler_text": previous_json.get("spoiler_text"),
+                "sensitive": sensitive,
+                "spoiler_text": spoiler_text,
                 "visibility": previous_json.get("visibility"),
                 "poll": null,
                 "language": previous_json.get("language"),
             }
         );
         debug!("Patched json: {:#?}", &patched_json);
-        let response = client
-            .put(url)
-            .header(
-                "Authorization",
-                format!("Bearer {}", self.config.get_mastodon_access_token()),
-            )
-            .header("Content-Type", "application/json")
-            .json(&patched_json)
-            .send()
-            .await?;
-        if response.status().is_success() {
-            debug!("Successfull message put {}", message_id);
-            Ok(())
-        } else {
-            Err(format!(
-                "Failed to put message, http status:\n{:#?}",
-                response.status()
-            )
-            .into())
+        if let Some(report) = &self.dry_run {
+            info!(
+                "Dry run, not updating message {}, payload:\n{:#}",
+                message_id, &patched_json
+            );
+            report.record(&ReportEntry {
+                status_id: message_id.clone(),
+                status_url: previous_json
+                    .get("url")
+                    .and_then(|url| url.as_str())
+                    .map(|url| url.to_string()),
+                descriptions: image_id_with_description.into_iter().collect(),
+                payload: patched_json,
+            })?;
+            return Ok(());
         }
+        self.send(
+            client
+                .put(url)
+                .header("Content-Type", "application/json")
+                .json(&patched_json),
+        )
+        .await?;
+        debug!("Successfull message put {}", message_id);
+        Ok(())
+    }
+
+    /// Posts status with direct visibility, which without mentions is seen only by own account.
+    pub async fn post_direct_message(&self, text: String) -> Result<()> {
+        if self.dry_run.is_some() {
+            info!("Dry run, not sending direct message: {}", text);
+            return Ok(());
+        }
+        let client = reqwest::Client::new();
+        let url = format!("{}/api/v1/statuses", self.config.get_mastodon_base_url());
+        self.send(client.post(url).json(&json!(
+            {
+                "status": text,
+                "visibility": "direct",
+            }
+        )))
+        .await?;
+        Ok(())
     }
 
     pub async fn put_json_of_message_with_retry(
@@ -176,8 +333,9 @@ impl MastodonPatch {
         json_string: String,
         message_id: String,
         image_id_with_description: HashMap<String, String>,
+        content_warning: Option<String>,
         retries: u64,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<()> {
         let mut retries = retries;
         loop {
             let result = self
@@ -185,13 +343,154 @@ impl MastodonPatch {
                     json_string.clone(),
                     message_id.clone(),
                     image_id_with_description.clone(),
+                    content_warning.clone(),
                 )
                 .await;
-            if result.is_ok() || retries == 0 {
-                return result;
+            match &result {
+                Err(err) if retries > 0 && err.is_retryable() => {
+                    warn!("Cannot put message {}, retrying: {}", message_id, err)
+                }
+                _ => return result,
             }
             retries -= 1;
-            std::thread::sleep(Duration::from_secs(5));
+            if !wait_or_cancel(&self.cancel, Duration::from_secs(5)).await {
+                return result;
+            }
+        }
+    }
+}
+
+// pauses all requests when server asks for it or remaining budget is low
+fn record_rate_limit(status: StatusCode, headers: &HeaderMap) {
+    let Some(pause) = rate_limit_pause(status, headers, Utc::now()) else {
+        return;
+    };
+    debug!("Rate limit: status {}, pausing for {:?}", status, pause);
+    let resume = Instant::now() + pause;
+    let mut paused_until = PAUSED_UNTIL.lock().unwrap();
+    if paused_until.is_none_or(|paused_until| paused_until < resume) {
+        *paused_until = Some(resume);
+    }
+}
+
+// time requests have to wait according to rate limit headers of response received at `now`
+fn rate_limit_pause(
+    status: StatusCode,
+    headers: &HeaderMap,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let until = |time: DateTime<Utc>| (time - now).to_std().ok();
+    if let Some(retry_after) = header("Retry-After") {
+        let pause = retry_after
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .map(Duration::from_secs)
+            .or_else(|| {
+                until(
+                    DateTime::parse_from_rfc2822(retry_after)
+                        .ok()?
+                        .with_timezone(&Utc),
+                )
+            });
+        if pause.is_some() {
+            return pause;
         }
     }
+    let remaining = header("X-RateLimit-Remaining").and_then(|value| value.parse::<u64>().ok());
+    if status == StatusCode::TOO_MANY_REQUESTS
+        || remaining.is_some_and(|remaining| remaining <= LOW_RATE_LIMIT_REMAINING)
+    {
+        return header("X-RateLimit-Reset")
+            .and_then(|reset| DateTime::parse_from_rfc3339(reset).ok())
+            .and_then(|reset| until(reset.with_timezone(&Utc)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let headers = headers(&[("Retry-After", " 30 ")]);
+        assert_eq!(
+            rate_limit_pause(StatusCode::TOO_MANY_REQUESTS, &headers, now()),
+            Some(Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn retry_after_as_date() {
+        let headers = headers(&[("Retry-After", "Wed, 01 May 2024 12:02:00 GMT")]);
+        assert_eq!(
+            rate_limit_pause(StatusCode::SERVICE_UNAVAILABLE, &headers, now()),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn retry_after_in_the_past_falls_back_to_reset() {
+        let headers = headers(&[
+            ("Retry-After", "Wed, 01 May 2024 11:00:00 GMT"),
+            ("X-RateLimit-Reset", "2024-05-01T12:05:00.000Z"),
+        ]);
+        assert_eq!(
+            rate_limit_pause(StatusCode::TOO_MANY_REQUESTS, &headers, now()),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn low_remaining_waits_for_reset() {
+        let headers = headers(&[
+            ("X-RateLimit-Remaining", "3"),
+            ("X-RateLimit-Reset", "2024-05-01T12:00:10+00:00"),
+        ]);
+        assert_eq!(
+            rate_limit_pause(StatusCode::OK, &headers, now()),
+            Some(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn enough_remaining_does_not_pause() {
+        let headers = headers(&[
+            ("X-RateLimit-Remaining", "250"),
+            ("X-RateLimit-Reset", "2024-05-01T12:00:10Z"),
+        ]);
+        assert_eq!(rate_limit_pause(StatusCode::OK, &headers, now()), None);
+    }
+
+    #[test]
+    fn reset_in_the_past_does_not_pause() {
+        let headers = headers(&[
+            ("X-RateLimit-Remaining", "0"),
+            ("X-RateLimit-Reset", "2024-05-01T11:59:00Z"),
+        ]);
+        assert_eq!(rate_limit_pause(StatusCode::OK, &headers, now()), None);
+    }
+
+    #[test]
+    fn invalid_headers_are_ignored() {
+        let headers = headers(&[("Retry-After", "soon"), ("X-RateLimit-Remaining", "few")]);
+        assert_eq!(rate_limit_pause(StatusCode::OK, &headers, now()), None);
+    }
 }
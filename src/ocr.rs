@@ -0,0 +1,76 @@
+use std::process::Stdio;
+
+use log::debug;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::OcrConfig;
+use crate::error::{Error, Result};
+
+/// Text recognized in the image by local OCR engine.
+#[derive(Debug, Clone)]
+pub struct Transcription {
+    pub text: String,
+    pub words: usize,
+}
+
+impl Transcription {
+    /// Screenshots of posts, documents and similar images contain many words, photos only a few.
+    pub fn is_text_heavy(&self, config: &OcrConfig) -> bool {
+        self.words >= config.min_words
+    }
+}
+
+/// Runs tesseract on image bytes passed through stdin.
+pub async fn transcribe(image: &[u8], config: &OcrConfig) -> Result<Transcription> {
+    let mut child = Command::new(&config.command)
+        .args(["stdin", "stdout", "-l", &config.languages])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| Error::invalid_response("OCR", "cannot write to stdin"))?;
+    stdin.write_all(image).await?;
+    // closing stdin tells tesseract that the whole image was sent
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(Error::invalid_response(
+            "OCR",
+            format!(
+                "{} exited with {}: {}",
+                config.command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    let text = clean(&String::from_utf8_lossy(&output.stdout));
+    // single characters and short fragments are usually noise recognized in photos
+    let words = text
+        .split_whitespace()
+        .filter(|word| word.chars().filter(|c| c.is_alphanumeric()).count() >= 3)
+        .count();
+    debug!("OCR recognized {} words: {}", words, text);
+    Ok(Transcription { text, words })
+}
+
+// joins lines of paragraphs and drops empty lines tesseract puts between them
+fn clean(text: &str) -> String {
+    text.split("\n\n")
+        .map(|paragraph| {
+            paragraph
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
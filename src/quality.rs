@@ -0,0 +1,208 @@
+use std::fmt;
+
+use crate::config::QualityConfig;
+use crate::language;
+
+// refusals are recognized only near the beginning, descriptions may legitimately quote such text later
+const REFUSAL_SEARCH_LENGTH: usize = 120;
+
+/// Reason why description returned by the model cannot be published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    TooShort,
+    Refusal,
+    WrongLanguage { expected: String, detected: String },
+}
+
+impl Rejection {
+    /// Extra instruction added to the prompt when asking again.
+    pub fn retry_hint(&self) -> String {
+        match self {
+            Rejection::TooShort => {
+                "Previous answer was empty or too short, describe the image in at least one full sentence.".to_string()
+            }
+            Rejection::Refusal => {
+                "Describe only what is visible in the image, such as objects, people's clothing and posture, setting, colours and any text.".to_string()
+            }
+            Rejection::WrongLanguage { expected, .. } => format!(
+                "Previous answer was written in wrong language, write the description only in language with code '{}'.",
+                expected
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::TooShort => write!(f, "description is empty or too short"),
+            Rejection::Refusal => write!(f, "model refused to describe the image"),
+            Rejection::WrongLanguage { expected, detected } => write!(
+                f,
+                "description is written in '{}' instead of '{}'",
+                detected, expected
+            ),
+        }
+    }
+}
+
+/// Checks description returned by the model and removes boilerplate from it.
+pub fn review(
+    description: &str,
+    lang_code: &str,
+    config: &QualityConfig,
+) -> Result<String, Rejection> {
+    let description = description
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\u{201c}' || c == '\u{201d}')
+        .trim();
+    let beginning: String = description
+        .chars()
+        .take(REFUSAL_SEARCH_LENGTH)
+        .collect::<String>()
+        .to_lowercase();
+    if config
+        .refusal_phrases
+        .iter()
+        .any(|phrase| beginning.contains(&phrase.to_lowercase()))
+    {
+        return Err(Rejection::Refusal);
+    }
+    let description = strip_filler(description, &config.filler_prefixes);
+    if description.chars().count() < config.min_length {
+        return Err(Rejection::TooShort);
+    }
+    if let Some(detected) = language::detect(&description) {
+        if !language::same_language(&detected, lang_code) {
+            return Err(Rejection::WrongLanguage {
+                expected: lang_code.to_string(),
+                detected,
+            });
+        }
+    }
+    Ok(description)
+}
+
+// "This image shows a cat" -> "A cat"
+fn strip_filler(description: &str, prefixes: &[String]) -> String {
+    let lowercase = description.to_lowercase();
+    let Some(prefix) = prefixes
+        .iter()
+        .filter(|prefix| lowercase.starts_with(&prefix.to_lowercase()))
+        .max_by_key(|prefix| prefix.len())
+    else {
+        return description.to_string();
+    };
+    // lowercasing may change byte length of some characters, so cut by characters
+    let rest: String = description.chars().skip(prefix.chars().count()).collect();
+    let rest = rest.trim_start_matches(|c: char| c == ',' || c == ':' || c.is_whitespace());
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn strip_filler_removes_prefix_and_capitalizes() {
+        let prefixes = prefixes(&["This image shows"]);
+        assert_eq!(strip_filler("This image shows a cat", &prefixes), "A cat");
+        assert_eq!(strip_filler("this IMAGE shows: a cat", &prefixes), "A cat");
+    }
+
+    #[test]
+    fn strip_filler_prefers_longest_prefix() {
+        let prefixes = prefixes(&["In the image", "In the image of"]);
+        assert_eq!(
+            strip_filler("In the image of a park, a dog runs", &prefixes),
+            "A park, a dog runs"
+        );
+    }
+
+    #[test]
+    fn strip_filler_keeps_other_descriptions() {
+        let prefixes = prefixes(&["This image shows"]);
+        assert_eq!(
+            strip_filler("A cat. This image shows it", &prefixes),
+            "A cat. This image shows it"
+        );
+        assert_eq!(strip_filler("This image shows", &prefixes), "");
+    }
+
+    #[test]
+    fn strip_filler_handles_multibyte_text() {
+        let prefixes = prefixes(&["Na zdjęciu widać"]);
+        assert_eq!(strip_filler("NA ZDJĘCIU WIDAĆ żółwia", &prefixes), "Żółwia");
+    }
+
+    #[test]
+    fn review_accepts_and_cleans_description() {
+        let config = QualityConfig::default();
+        assert_eq!(
+            review(
+                "\"This image shows a black cat sleeping on a red sofa.\"",
+                "en",
+                &config
+            ),
+            Ok("A black cat sleeping on a red sofa.".to_string())
+        );
+    }
+
+    #[test]
+    fn review_rejects_refusal() {
+        let config = QualityConfig::default();
+        assert_eq!(
+            review(
+                "I'm sorry, but I can't describe people in this image.",
+                "en",
+                &config
+            ),
+            Err(Rejection::Refusal)
+        );
+    }
+
+    #[test]
+    fn review_ignores_refusal_phrase_far_from_beginning() {
+        let config = QualityConfig::default();
+        let description = format!(
+            "{} A sign says: I'm sorry, we are closed.",
+            "A small bakery with a green wooden door and a large window full of bread, croissants \
+             and cakes, standing on a quiet cobbled street in the old town."
+        );
+        assert!(review(&description, "en", &config).is_ok());
+    }
+
+    #[test]
+    fn review_rejects_short_description() {
+        let config = QualityConfig::default();
+        assert_eq!(review("  ", "en", &config), Err(Rejection::TooShort));
+        assert_eq!(
+            review("This image shows a cat", "en", &config),
+            Err(Rejection::TooShort)
+        );
+    }
+
+    #[test]
+    fn review_rejects_wrong_language() {
+        let config = QualityConfig::default();
+        assert_eq!(
+            review(
+                "A black cat is sleeping on a red sofa next to an open window in the afternoon.",
+                "pl",
+                &config
+            ),
+            Err(Rejection::WrongLanguage {
+                expected: "pl".to_string(),
+                detected: "en".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::Config;
+
+// rough cost of single image in prompt, used to estimate tokens before the request is sent
+const IMAGE_TOKENS_ESTIMATE: usize = 765;
+
+/// Token bucket refilled continuously, `capacity` units per minute.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn per_minute(capacity: usize) -> Self {
+        let capacity = capacity.max(1) as f64;
+        Self {
+            capacity,
+            per_second: capacity / 60.0,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Waits until `amount` units are available and takes them.
+    pub async fn acquire(&self, amount: usize) {
+        // request bigger than whole bucket would never fit, let it drain the bucket instead
+        let amount = (amount as f64).min(self.capacity);
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let (tokens, last) = *state;
+                let now = Instant::now();
+                let tokens = (tokens + now.duration_since(last).as_secs_f64() * self.per_second)
+                    .min(self.capacity);
+                if tokens >= amount {
+                    *state = (tokens - amount, now);
+                    return;
+                }
+                *state = (tokens, now);
+                Duration::from_secs_f64((amount - tokens) / self.per_second)
+            };
+            debug!(
+                "Rate limit reached, waiting {:.1} seconds",
+                wait.as_secs_f64()
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limits concurrency and rate of vision API requests, shared by all loops.
+#[derive(Debug)]
+pub struct VisionLimiter {
+    concurrent: Semaphore,
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl VisionLimiter {
+    pub fn from_config(config: &Config) -> Self {
+        let limits = config.get_gpt_limits();
+        Self {
+            concurrent: Semaphore::new(limits.max_concurrent_requests.max(1)),
+            requests: limits.requests_per_minute.map(TokenBucket::per_minute),
+            tokens: limits.tokens_per_minute.map(TokenBucket::per_minute),
+        }
+    }
+
+    /// Waits for free slot and rate limit budget, request may be sent while permit is held.
+    pub async fn acquire(
+        &self,
+        prompt: &str,
+        images: usize,
+        max_tokens: usize,
+    ) -> SemaphorePermit<'_> {
+        let permit = self
+            .concurrent
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        if let Some(requests) = &self.requests {
+            requests.acquire(1).await;
+        }
+        if let Some(tokens) = &self.tokens {
+            // roughly 4 characters per token
+            tokens
+                .acquire(prompt.len() / 4 + IMAGE_TOKENS_ESTIMATE * images + max_tokens)
+                .await;
+        }
+        permit
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Local;
+use serde::Serialize;
+
+use crate::error::Result;
+
+pub const DEFAULT_REPORT_PATH: &str = "dry_run_report.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportFormat {
+    JsonLines,
+    Markdown,
+}
+
+/// What would have been sent to Mastodon for single status.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportEntry {
+    pub status_id: String,
+    pub status_url: Option<String>,
+    pub descriptions: BTreeMap<String, String>,
+    pub payload: serde_json::Value,
+}
+
+/// Collects edits which were not sent because of dry-run mode, for review before real run.
+#[derive(Debug)]
+pub struct DryRunReport {
+    path: PathBuf,
+    format: ReportFormat,
+    file: Mutex<File>,
+}
+
+impl DryRunReport {
+    /// Opens report for appending, Markdown is used for `.md` files and JSON Lines otherwise.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("md") | Some("markdown") => ReportFormat::Markdown,
+            _ => ReportFormat::JsonLines,
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, entry: &ReportEntry) -> Result<()> {
+        let text = match self.format {
+            ReportFormat::JsonLines => format!("{}\n", serde_json::to_string(entry)?),
+            ReportFormat::Markdown => {
+                let mut text = format!(
+                    "## Status {} ({})\n\n",
+                    entry.status_id,
+                    Local::now().format("%Y-%m-%d %H:%M:%S")
+                );
+                if let Some(url) = &entry.status_url {
+                    text.push_str(&format!("<{}>\n\n", url));
+                }
+                for (attachment_id, description) in &entry.descriptions {
+                    text.push_str(&format!("- **{}**: {}\n", attachment_id, description));
+                }
+                text.push_str(&format!(
+                    "\n```json\n{}\n```\n\n",
+                    serde_json::to_string_pretty(&entry.payload)?
+                ));
+                text
+            }
+        };
+        let mut file = self.file.lock().unwrap();
+        file.write_all(text.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
@@ -1,6 +1,14 @@
+use log::error;
 use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Serialize};
 
-use std::{collections::HashSet, fs::File, io::Read, sync::Mutex};
+use std::{
+    collections::HashSet, fs::File, io::ErrorKind, io::Read, io::Write, path::Path, sync::Mutex,
+};
+
+use crate::error::{Error, Result};
+
+const ALREADY_PARSED_FILE: &str = "already_parsed.json";
 
 pub struct SharedData {
     pub already_parsed: HashSet<String>,
@@ -14,24 +22,67 @@ impl Default for SharedData {
 
 impl SharedData {
     pub fn new() -> Self {
-        let file = File::open("already_parsed.json");
-        let already_parsed: HashSet<String> = match file {
-            Ok(mut file) => {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).unwrap();
-                serde_json::from_str(&contents).unwrap()
-            }
-            Err(_) => HashSet::new(),
-        };
-        Self { already_parsed }
+        Self {
+            already_parsed: load_json_state_or_default(ALREADY_PARSED_FILE),
+        }
+    }
+
+    pub fn load() -> Result<Self> {
+        let already_parsed = load_json_state(ALREADY_PARSED_FILE)?;
+        Ok(Self { already_parsed })
     }
 
-    pub fn save(&self) {
-        let file = File::create("already_parsed.json").unwrap();
-        serde_json::to_writer(file, &self.already_parsed).unwrap();
+    pub fn save(&self) -> Result<()> {
+        save_json_state(ALREADY_PARSED_FILE, &self.already_parsed)
     }
 }
 
+/// Writes to temporary file first and renames it, so the file is never left truncated.
+pub fn write_atomically(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Reads state saved by `save_json_state`, missing file means nothing was saved yet.
+pub fn load_json_state<T: DeserializeOwned + Default>(path: &str) -> Result<T> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)
+                .map_err(|err| Error::state_store(path, err))?;
+            serde_json::from_str(&contents).map_err(|err| Error::state_store(path, err))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(Error::state_store(path, err)),
+    }
+}
+
+/// Like `load_json_state`, but starts empty if the file is damaged. The damaged file is moved
+/// aside first, so saving new state does not overwrite what could still be recovered from it.
+pub fn load_json_state_or_default<T: DeserializeOwned + Default>(path: &str) -> T {
+    load_json_state(path).unwrap_or_else(|err| {
+        let aside = format!("{}.broken", path);
+        match std::fs::rename(path, &aside) {
+            Ok(()) => error!("{}, moved it to {} and starting empty", err, aside),
+            Err(rename_err) => error!(
+                "{}, cannot move it aside ({}), starting empty",
+                err, rename_err
+            ),
+        }
+        T::default()
+    })
+}
+
+pub fn save_json_state<T: Serialize>(path: &str, state: &T) -> Result<()> {
+    let contents = serde_json::to_vec(state)?;
+    write_atomically(path, &contents).map_err(|err| Error::state_store(path, err))
+}
+
 pub static SHARED_DATA: Lazy<Mutex<SharedData>> = Lazy::new(|| Mutex::new(SharedData::new()));
 
 pub fn get_shared_data() -> &'static Mutex<SharedData> {
@@ -0,0 +1,95 @@
+use serde_json::Value;
+use voca_rs::strip::strip_tags;
+
+use crate::config::ThreadContextConfig;
+use crate::language::shorten;
+
+/// Surroundings of the status given to the model, so images in threads are described in context.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadContext {
+    /// Content warning of the status.
+    pub spoiler_text: String,
+    /// Author and text of preceding posts of the thread, oldest first.
+    pub ancestors: Vec<(String, String)>,
+    /// Author and text of quoted post.
+    pub quote: Option<(String, String)>,
+}
+
+impl ThreadContext {
+    /// Reads ancestors from response of `/api/v1/statuses/:id/context`, keeping the closest ones.
+    pub fn with_ancestors(mut self, context: &Value, max_ancestors: usize) -> Self {
+        let ancestors = context
+            .get("ancestors")
+            .and_then(|ancestors| ancestors.as_array())
+            .cloned()
+            .unwrap_or_default();
+        let skip = ancestors.len().saturating_sub(max_ancestors);
+        self.ancestors = ancestors.iter().skip(skip).filter_map(post_of).collect();
+        self
+    }
+
+    /// Reads quoted post from status, both Mastodon (`quote.quoted_status`) and older
+    /// (`quote` being the status) formats are supported.
+    pub fn with_quote(mut self, status: &Value) -> Self {
+        self.quote = status
+            .get("quote")
+            .and_then(|quote| quote.get("quoted_status").or(Some(quote)))
+            .and_then(post_of);
+        self
+    }
+
+    /// Formats context with text of the status itself, shortened to configured length.
+    pub fn to_prompt(&self, content: &str, config: &ThreadContextConfig) -> String {
+        let mut lines = Vec::new();
+        if !self.spoiler_text.is_empty() {
+            lines.push(format!("Content warning: {}", self.spoiler_text));
+        }
+        // each post and the quote get equal share of the length
+        let shares = self.ancestors.len() + usize::from(self.quote.is_some());
+        let per_post = config.max_length / shares.max(1);
+        if !self.ancestors.is_empty() {
+            lines.push("Earlier posts in the conversation:".to_string());
+            for (author, text) in &self.ancestors {
+                lines.push(format!("@{}: {}", author, shorten(text, per_post)));
+            }
+        }
+        if let Some((author, text)) = &self.quote {
+            lines.push(format!(
+                "Quoted post by @{}: {}",
+                author,
+                shorten(text, per_post)
+            ));
+        }
+        if lines.is_empty() {
+            return content.to_string();
+        }
+        lines.push(format!("Post: {}", text_of(content)));
+        lines.join("\n")
+    }
+}
+
+fn post_of(status: &Value) -> Option<(String, String)> {
+    let author = status.pointer("/account/acct")?.as_str()?.to_string();
+    let content = status.get("content")?.as_str()?;
+    let spoiler_text = status
+        .get("spoiler_text")
+        .and_then(|spoiler_text| spoiler_text.as_str())
+        .unwrap_or_default();
+    let text = if spoiler_text.is_empty() {
+        text_of(content)
+    } else {
+        format!("[CW: {}] {}", spoiler_text, text_of(content))
+    };
+    Some((author, text))
+}
+
+fn text_of(content: &str) -> String {
+    strip_tags(
+        &content
+            .replace("</p><p>", "\n")
+            .replace("<br />", "\n")
+            .replace("<br>", "\n"),
+    )
+    .trim()
+    .to_string()
+}
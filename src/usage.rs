@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::{fmt, sync::Mutex};
+
+use chrono::{Datelike, Local, NaiveDate, NaiveTime};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{BudgetConfig, ModelPrice};
+use crate::error::Result;
+use crate::shared_data::{load_json_state, load_json_state_or_default, save_json_state};
+
+const USAGE_FILE: &str = "usage.json";
+
+// daily totals older than this are dropped, monthly totals only need current month
+const KEPT_DAYS: i64 = 400;
+
+/// Tokens reported by the provider for single request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    /// Reads `usage` object of chat completion response.
+    pub fn from_response(body: &serde_json::Value) -> Option<Self> {
+        let usage = body.get("usage")?;
+        Some(Self {
+            prompt_tokens: usage.get("prompt_tokens")?.as_u64()?,
+            completion_tokens: usage
+                .get("completion_tokens")
+                .and_then(|tokens| tokens.as_u64())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Cost in USD, prices are per million tokens.
+    pub fn cost(&self, price: &ModelPrice) -> f64 {
+        (self.prompt_tokens as f64 * price.prompt
+            + self.completion_tokens as f64 * price.completion)
+            / 1_000_000.0
+    }
+}
+
+/// Sum of usage over some period.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UsageTotal {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Cost in USD.
+    pub cost: f64,
+}
+
+impl UsageTotal {
+    fn add(&mut self, other: &UsageTotal) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetPeriod {
+    Daily,
+    Monthly,
+}
+
+impl fmt::Display for BudgetPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetPeriod::Daily => write!(f, "daily"),
+            BudgetPeriod::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+/// Spending cap which was reached.
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetExceeded {
+    pub period: BudgetPeriod,
+    pub spent: f64,
+    pub cap: f64,
+}
+
+impl BudgetExceeded {
+    /// Time until the period rolls over and spending starts from zero.
+    pub fn resets_in(&self) -> Duration {
+        next_period(self.period, Local::now().date_naive())
+            .and_then(|next| {
+                next.and_time(NaiveTime::MIN)
+                    .and_local_timezone(Local)
+                    .earliest()
+            })
+            .and_then(|next| (next - Local::now()).to_std().ok())
+            .unwrap_or(Duration::from_secs(60))
+    }
+
+    // identifies period, so the cap is reported only once per period
+    fn key(&self, today: NaiveDate) -> String {
+        match self.period {
+            BudgetPeriod::Daily => format!("daily:{}", today),
+            BudgetPeriod::Monthly => format!("monthly:{}", today.format("%Y-%m")),
+        }
+    }
+}
+
+/// First day of the period following the one containing `today`.
+fn next_period(period: BudgetPeriod, today: NaiveDate) -> Option<NaiveDate> {
+    match period {
+        BudgetPeriod::Daily => today.succ_opt(),
+        BudgetPeriod::Monthly => today
+            .with_day(1)
+            .and_then(|first| first.checked_add_months(chrono::Months::new(1))),
+    }
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} budget of ${:.2} reached (spent ${:.2})",
+            self.period, self.cap, self.spent
+        )
+    }
+}
+
+/// Daily usage totals persisted between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UsageLedger {
+    days: BTreeMap<NaiveDate, UsageTotal>,
+    /// Last period in which reaching the cap was reported.
+    #[serde(default)]
+    notified: Option<String>,
+}
+
+impl UsageLedger {
+    pub fn new() -> Self {
+        load_json_state_or_default(USAGE_FILE)
+    }
+
+    pub fn load() -> Result<Self> {
+        load_json_state(USAGE_FILE)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json_state(USAGE_FILE, self)
+    }
+
+    /// Adds usage of single request to today's total and returns its cost.
+    pub fn record(&mut self, usage: Usage, price: Option<&ModelPrice>) -> f64 {
+        let cost = price.map(|price| usage.cost(price)).unwrap_or_default();
+        let today = Local::now().date_naive();
+        self.days.entry(today).or_default().add(&UsageTotal {
+            requests: 1,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            cost,
+        });
+        let oldest = today - chrono::Duration::days(KEPT_DAYS);
+        self.days.retain(|day, _| *day >= oldest);
+        cost
+    }
+
+    pub fn today(&self) -> UsageTotal {
+        self.day(Local::now().date_naive())
+    }
+
+    pub fn this_month(&self) -> UsageTotal {
+        self.month(Local::now().date_naive())
+    }
+
+    fn day(&self, day: NaiveDate) -> UsageTotal {
+        self.days.get(&day).copied().unwrap_or_default()
+    }
+
+    /// Total of the month containing `today`.
+    fn month(&self, today: NaiveDate) -> UsageTotal {
+        let mut total = UsageTotal::default();
+        for (_, day) in self
+            .days
+            .iter()
+            .filter(|(day, _)| day.year() == today.year() && day.month() == today.month())
+        {
+            total.add(day);
+        }
+        total
+    }
+
+    /// Returns cap which is reached by current spend, monthly one first as it lasts longer.
+    pub fn exceeded_cap(&self, budget: &BudgetConfig) -> Option<BudgetExceeded> {
+        self.exceeded_cap_on(budget, Local::now().date_naive())
+    }
+
+    fn exceeded_cap_on(&self, budget: &BudgetConfig, today: NaiveDate) -> Option<BudgetExceeded> {
+        [
+            (BudgetPeriod::Monthly, budget.monthly, self.month(today)),
+            (BudgetPeriod::Daily, budget.daily, self.day(today)),
+        ]
+        .into_iter()
+        .find_map(|(period, cap, total)| {
+            let cap = cap?;
+            (total.cost >= cap).then_some(BudgetExceeded {
+                period,
+                spent: total.cost,
+                cap,
+            })
+        })
+    }
+
+    /// Returns true only the first time the cap is reached in its period.
+    pub fn mark_notified(&mut self, exceeded: &BudgetExceeded) -> bool {
+        let first = self.mark_notified_on(exceeded, Local::now().date_naive());
+        if first {
+            if let Err(err) = self.save() {
+                error!("{}", err);
+            }
+        }
+        first
+    }
+
+    fn mark_notified_on(&mut self, exceeded: &BudgetExceeded, today: NaiveDate) -> bool {
+        let key = exceeded.key(today);
+        if self.notified.as_ref() == Some(&key) {
+            return false;
+        }
+        self.notified = Some(key);
+        true
+    }
+}
+
+pub static USAGE: Lazy<Mutex<UsageLedger>> = Lazy::new(|| Mutex::new(UsageLedger::new()));
+
+/// Records usage of request to given model in persisted totals and logs spend so far.
+pub fn record_usage(model: &str, usage: Usage, price: Option<ModelPrice>) {
+    let mut ledger = USAGE.lock().unwrap();
+    let cost = ledger.record(usage, price.as_ref());
+    if let Err(err) = ledger.save() {
+        error!("{}", err);
+    }
+    if price.is_none() {
+        info!(
+            "No price configured for model {}, cost of request is not counted",
+            model
+        );
+    }
+    info!(
+        "Used {} prompt and {} completion tokens (${:.4}), spent today: ${:.2}, this month: ${:.2}",
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        cost,
+        ledger.today().cost,
+        ledger.this_month().cost
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn spent(days: &[(NaiveDate, f64)]) -> UsageLedger {
+        UsageLedger {
+            days: days
+                .iter()
+                .map(|(day, cost)| {
+                    (
+                        *day,
+                        UsageTotal {
+                            requests: 1,
+                            cost: *cost,
+                            ..UsageTotal::default()
+                        },
+                    )
+                })
+                .collect(),
+            notified: None,
+        }
+    }
+
+    fn budget(daily: Option<f64>, monthly: Option<f64>) -> BudgetConfig {
+        BudgetConfig {
+            daily,
+            monthly,
+            notify: false,
+        }
+    }
+
+    fn exceeded(period: BudgetPeriod) -> BudgetExceeded {
+        BudgetExceeded {
+            period,
+            spent: 1.0,
+            cap: 1.0,
+        }
+    }
+
+    #[test]
+    fn monthly_cap_is_reported_before_daily() {
+        let today = date(2024, 5, 10);
+        let ledger = spent(&[(date(2024, 5, 1), 8.0), (today, 3.0)]);
+        let exceeded = ledger
+            .exceeded_cap_on(&budget(Some(2.0), Some(10.0)), today)
+            .unwrap();
+        assert_eq!(exceeded.period, BudgetPeriod::Monthly);
+        assert_eq!(exceeded.spent, 11.0);
+        assert_eq!(exceeded.cap, 10.0);
+    }
+
+    #[test]
+    fn daily_cap_is_reported_when_monthly_is_not_reached() {
+        let today = date(2024, 5, 10);
+        let ledger = spent(&[(date(2024, 5, 1), 1.0), (today, 3.0)]);
+        let exceeded = ledger
+            .exceeded_cap_on(&budget(Some(2.0), Some(10.0)), today)
+            .unwrap();
+        assert_eq!(exceeded.period, BudgetPeriod::Daily);
+        assert_eq!(exceeded.spent, 3.0);
+    }
+
+    #[test]
+    fn cap_is_reached_at_exact_amount() {
+        let today = date(2024, 5, 10);
+        let ledger = spent(&[(today, 2.0)]);
+        assert!(ledger
+            .exceeded_cap_on(&budget(Some(2.0), None), today)
+            .is_some());
+        assert!(ledger
+            .exceeded_cap_on(&budget(Some(2.01), None), today)
+            .is_none());
+    }
+
+    #[test]
+    fn no_cap_is_never_reached() {
+        let today = date(2024, 5, 10);
+        let ledger = spent(&[(today, 100.0)]);
+        assert!(ledger.exceeded_cap_on(&budget(None, None), today).is_none());
+    }
+
+    #[test]
+    fn spend_of_previous_month_is_not_counted() {
+        let today = date(2024, 6, 1);
+        let ledger = spent(&[(date(2024, 5, 31), 9.0), (date(2023, 6, 15), 9.0)]);
+        assert!(ledger
+            .exceeded_cap_on(&budget(Some(1.0), Some(5.0)), today)
+            .is_none());
+        assert_eq!(ledger.month(date(2024, 5, 20)).cost, 9.0);
+    }
+
+    #[test]
+    fn next_period_rolls_over_month_and_year() {
+        assert_eq!(
+            next_period(BudgetPeriod::Daily, date(2024, 1, 31)),
+            Some(date(2024, 2, 1))
+        );
+        assert_eq!(
+            next_period(BudgetPeriod::Daily, date(2024, 12, 31)),
+            Some(date(2025, 1, 1))
+        );
+        assert_eq!(
+            next_period(BudgetPeriod::Monthly, date(2024, 1, 31)),
+            Some(date(2024, 2, 1))
+        );
+        assert_eq!(
+            next_period(BudgetPeriod::Monthly, date(2024, 12, 15)),
+            Some(date(2025, 1, 1))
+        );
+    }
+
+    #[test]
+    fn reset_is_within_the_period() {
+        let daily = exceeded(BudgetPeriod::Daily).resets_in();
+        assert!(daily > Duration::ZERO && daily <= Duration::from_secs(25 * 3600));
+        let monthly = exceeded(BudgetPeriod::Monthly).resets_in();
+        assert!(monthly > Duration::ZERO && monthly <= Duration::from_secs(32 * 24 * 3600));
+    }
+
+    #[test]
+    fn cap_is_notified_once_per_period() {
+        let mut ledger = UsageLedger::default();
+        let daily = exceeded(BudgetPeriod::Daily);
+        assert!(ledger.mark_notified_on(&daily, date(2024, 5, 10)));
+        assert!(!ledger.mark_notified_on(&daily, date(2024, 5, 10)));
+        assert!(ledger.mark_notified_on(&daily, date(2024, 5, 11)));
+    }
+
+    #[test]
+    fn monthly_cap_is_notified_once_per_month() {
+        let mut ledger = UsageLedger::default();
+        let monthly = exceeded(BudgetPeriod::Monthly);
+        assert!(ledger.mark_notified_on(&monthly, date(2024, 5, 10)));
+        assert!(!ledger.mark_notified_on(&monthly, date(2024, 5, 31)));
+        assert!(ledger.mark_notified_on(&monthly, date(2024, 6, 1)));
+    }
+}
@@ -1,30 +1,266 @@
-use std::error::Error;
+use std::sync::Arc;
 
-use log::debug;
-use log::error;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::quality::review;
+use crate::rate_limit::VisionLimiter;
+use crate::usage::{record_usage, Usage};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use voca_rs::strip::strip_tags;
-pub struct Vision();
+
+/// Description of image generated by the model.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Description {
+    /// Short description published as alt text.
+    pub alt_text: String,
+    /// Longer description with details which don't fit alt text.
+    pub long_description: String,
+    /// Text visible in the image, verbatim.
+    pub detected_text: String,
+    /// Suggested hashtags, without `#`.
+    pub hashtags: Vec<String>,
+    /// Topics which may need content warning, e.g. "spiders".
+    pub content_warnings: Vec<String>,
+}
+
+impl Description {
+    /// Description consisting only of alt text, e.g. when structured output is disabled.
+    pub fn from_alt_text(alt_text: String) -> Self {
+        Self {
+            alt_text,
+            ..Self::default()
+        }
+    }
+}
+
+pub struct Vision {
+    config: Arc<Config>,
+    limiter: Option<Arc<VisionLimiter>>,
+    transcription: Option<String>,
+}
 
 impl Vision {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            config,
+            limiter: None,
+            transcription: None,
+        }
+    }
+
+    /// Requests wait for the shared limiter before they are sent.
+    pub fn with_limiter(mut self, limiter: Arc<VisionLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Text recognized in the image by OCR is given to the model, so it can quote it accurately.
+    pub fn with_transcription(mut self, transcription: Option<String>) -> Self {
+        self.transcription = transcription;
+        self
+    }
+
+    /// Downloads image, for steps which need its content rather than URL.
+    pub async fn download_image(image_url: &str) -> Result<Arc<[u8]>> {
+        let response = reqwest::get(image_url).await?.error_for_status()?;
+        Ok(response.bytes().await?.to_vec().into())
+    }
+
+    /// Turns image URL or path to local file into URL accepted by the API (data URL for files).
+    pub async fn image_url_from_source(source: &str) -> Result<String> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(source.to_string());
+        }
+        let bytes = tokio::fs::read(source).await?;
+        let extension = std::path::Path::new(source)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let mime = match extension.as_str() {
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            _ => "image/jpeg",
+        };
+        Ok(format!("data:{};base64,{}", mime, STANDARD.encode(bytes)))
+    }
+
+    /// Generates description and checks it, asking again or trying fallback model if it is unusable.
     pub async fn get_description(
         &self,
         image_url: String,
         lang_code: String,
         context: String,
-    ) -> Result<String, Box<dyn Error>> {
-        let config = crate::config::Config::from_json();
+    ) -> Result<Description> {
+        let mut descriptions = self
+            .get_descriptions(vec![image_url], lang_code, context)
+            .await?;
+        Ok(descriptions.remove(0))
+    }
+
+    /// Describes several images of one status in single request, so the model can see they belong
+    /// together (e.g. comic or sequence of photos). Returns descriptions in order of images.
+    pub async fn get_descriptions(
+        &self,
+        image_urls: Vec<String>,
+        lang_code: String,
+        context: String,
+    ) -> Result<Vec<Description>> {
+        let quality = self.config.get_quality_config();
+        let models = std::iter::once(self.config.get_model()).chain(quality.fallback_model.clone());
+        let mut rejection = None;
+        for model in models {
+            let mut hint = None;
+            'attempts: for _ in 0..=quality.retries {
+                let mut descriptions = self
+                    .request_descriptions(
+                        &model,
+                        &image_urls,
+                        &lang_code,
+                        &context,
+                        hint.as_deref(),
+                    )
+                    .await?;
+                for description in descriptions.iter_mut() {
+                    match review(&description.alt_text, &lang_code, &quality) {
+                        Ok(alt_text) => description.alt_text = alt_text,
+                        Err(reason) => {
+                            warn!(
+                                "Rejected description from {}, {}: {}",
+                                model, reason, description.alt_text
+                            );
+                            hint = Some(reason.retry_hint());
+                            rejection = Some(reason);
+                            continue 'attempts;
+                        }
+                    }
+                }
+                return Ok(descriptions);
+            }
+        }
+        Err(Error::LowQuality(
+            rejection
+                .map(|reason| reason.to_string())
+                .unwrap_or_default(),
+        ))
+    }
+
+    async fn request_descriptions(
+        &self,
+        model: &str,
+        image_urls: &[String],
+        lang_code: &str,
+        context: &str,
+        hint: Option<&str>,
+    ) -> Result<Vec<Description>> {
+        let config = &self.config;
+        let structured = config.get_structured_output();
+        let batch = image_urls.len() > 1;
         let client = reqwest::Client::new();
-        let context = strip_tags(&context);
-        let prompt = format!(
-            "Please describe this image to visually impaired user.
+        let context = strip_tags(context);
+        let prompt = if batch {
+            format!(
+                "Please describe each of these {} images to visually impaired user.
+        Images belong to the same post in this order, they may form a sequence or comic.
+        Please be as descriptive as possible, but keep each description relatively short.
+        You must write descriptions in language with following BCP 47 code: '{}'
+        Use following context of message if needed: '{}'",
+                image_urls.len(),
+                lang_code,
+                context
+            )
+        } else {
+            format!(
+                "Please describe this image to visually impaired user.
         Please be as descriptive as possible, but keep it relatively short.
-        You must write description in language with following two letter code: '{}'
+        You must write description in language with following BCP 47 code: '{}'
         Use following context of message if needed: '{}'",
-            lang_code, context
-        );
-        let prompt = textwrap::dedent(&prompt);
+                lang_code, context
+            )
+        };
+        let mut prompt = textwrap::dedent(&prompt);
+        if let Some(transcription) = &self.transcription {
+            prompt.push_str(&format!(
+                "\nImage contains following text recognized by OCR, quote it exactly where relevant: '{}'",
+                transcription
+            ));
+        }
+        if let Some(hint) = hint {
+            prompt.push('\n');
+            prompt.push_str(hint);
+        }
+        if batch {
+            prompt.push_str(if structured {
+                BATCH_STRUCTURED_OUTPUT_PROMPT
+            } else {
+                BATCH_OUTPUT_PROMPT
+            });
+        }
+        if structured {
+            prompt.push_str(STRUCTURED_OUTPUT_PROMPT);
+            let content_warnings = config.get_content_warning_config();
+            if content_warnings.enabled {
+                prompt.push_str(&format!(
+                    "\nFor content_warnings use only these topics: {}",
+                    content_warnings.topics.join(", ")
+                ));
+            }
+        }
         debug!("Prompt: {}", &prompt);
+        let max_tokens = config.get_max_tokens() * image_urls.len();
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(limiter.acquire(&prompt, image_urls.len(), max_tokens).await),
+            None => None,
+        };
+        let mut content = vec![json!({
+            "type": "text",
+            "text": prompt
+        })];
+        content.extend(image_urls.iter().map(|image_url| {
+            json!({
+                "type": "image_url",
+                "image_url": {
+                    "url": image_url
+                }
+            })
+        }));
+        let mut request = json!({
+            "model": model,
+            "messages": [
+                {
+                    "role": "user",
+                    "content": content
+                }
+            ],
+            "max_tokens": max_tokens,
+        });
+        if structured {
+            let schema = if batch {
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "descriptions": { "type": "array", "items": description_schema() },
+                    },
+                    "required": ["descriptions"],
+                    "additionalProperties": false,
+                })
+            } else {
+                description_schema()
+            };
+            request["response_format"] = json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": "image_description",
+                    "strict": true,
+                    "schema": schema,
+                }
+            });
+        }
         let response = client
             .post("https://api.openai.com/v1/chat/completions")
             .header("Content-Type", "application/json")
@@ -32,63 +268,130 @@ impl Vision {
                 "Authorization",
                 format!("Bearer {}", config.get_gpt_api_key()),
             )
-            .json(&json!({
-                "model": config.get_model(),
-                "messages": [
-                    {
-                        "role": "user",
-                        "content": [
-                            {
-                                "type": "text",
-                                "text": prompt
-                            },
-                            {
-                                "type": "image_url",
-                                "image_url": {
-                                    "url": image_url
-                                }
-                            }
-                        ]
-                    }
-                ],
-                "max_tokens": config.get_max_tokens(),
-            }))
+            .json(&request)
             .send()
             .await?;
-        if !response.status().is_success() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "ChatGPT API returned error, http code: {}",
-                    response.status()
-                ),
-            )));
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            error!(
+                "ChatGPT API returned error, http code: {}\n{}",
+                status, body
+            );
+            if body.contains("content_policy_violation") {
+                return Err(Error::ContentPolicy(body));
+            }
+            return Err(Error::ProviderApi { status, body });
         }
         let body: Value = response.json().await?;
         debug!("Full response from ChatGPT API: {:#?}", body);
-        let error = body
-            .as_object()
-            .ok_or("Cannot get body as JSON object")?
-            .get("error")
-            .ok_or("Cannot get error from JSON object");
-        match error {
-            Ok(error) => {
-                error!("ChatGPT API returned error:\n{:#?}", error);
-            }
-            Err(_) => {
-                error!("ChatGPT API returned unknown error.\n{:#?}", body);
-            }
+        if let Some(usage) = Usage::from_response(&body) {
+            record_usage(model, usage, config.get_price_of(model));
         }
-        let choices = body
-            .as_object()
-            .unwrap()
+        if let Some(error) = body.get("error") {
+            error!("ChatGPT API returned error:\n{:#?}", error);
+            return Err(Error::ProviderApi {
+                status,
+                body: error.to_string(),
+            });
+        }
+        let first_choice = body
             .get("choices")
-            .unwrap()
-            .as_array()
-            .unwrap();
-        let first_choice = choices.first().unwrap().as_object().unwrap();
-        let message = first_choice.get("message").unwrap().as_object().unwrap();
-        let content = message.get("content").unwrap().as_str().unwrap();
-        Ok(content.to_string())
+            .and_then(|choices| choices.get(0))
+            .ok_or_else(|| Error::invalid_response("ChatGPT API", "no choices in response"))?;
+        if first_choice
+            .get("finish_reason")
+            .and_then(|reason| reason.as_str())
+            == Some("content_filter")
+        {
+            return Err(Error::ContentPolicy(
+                "response was stopped by content filter".to_string(),
+            ));
+        }
+        if let Some(refusal) = first_choice
+            .pointer("/message/refusal")
+            .and_then(|refusal| refusal.as_str())
+        {
+            return Err(Error::ContentPolicy(refusal.to_string()));
+        }
+        let content = first_choice
+            .pointer("/message/content")
+            .and_then(|content| content.as_str())
+            .ok_or_else(|| {
+                Error::invalid_response("ChatGPT API", "no message content in response")
+            })?;
+        let descriptions = match (batch, structured) {
+            (false, false) => vec![Description::from_alt_text(content.to_string())],
+            (false, true) => vec![parse_json(content)?],
+            (true, true) => parse_json::<BatchDescriptions<Description>>(content)?.descriptions,
+            (true, false) => parse_json::<BatchDescriptions<String>>(json_object_of(content))?
+                .descriptions
+                .into_iter()
+                .map(Description::from_alt_text)
+                .collect(),
+        };
+        if descriptions.len() != image_urls.len() {
+            return Err(Error::invalid_response(
+                "ChatGPT API",
+                format!(
+                    "got {} descriptions for {} images",
+                    descriptions.len(),
+                    image_urls.len()
+                ),
+            ));
+        }
+        Ok(descriptions)
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchDescriptions<T> {
+    descriptions: Vec<T>,
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(content: &str) -> Result<T> {
+    serde_json::from_str(content).map_err(|err| {
+        Error::invalid_response(
+            "ChatGPT API",
+            format!("description is not valid JSON ({}): {}", err, content),
+        )
+    })
+}
+
+// without structured output the JSON may be wrapped in markdown code block or text
+fn json_object_of(content: &str) -> &str {
+    match (content.find('{'), content.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &content[start..=end],
+        _ => content,
     }
 }
+
+const BATCH_OUTPUT_PROMPT: &str = "
+Answer only with JSON object with field descriptions - array with one description string per image, in order of images.";
+
+const BATCH_STRUCTURED_OUTPUT_PROMPT: &str = "
+Answer with JSON object with field descriptions - array with one object per image, in order of images.";
+
+const STRUCTURED_OUTPUT_PROMPT: &str = "
+Description is JSON object with following fields:
+alt_text - the description, at most few sentences,
+long_description - detailed description for those who want to know more,
+detected_text - text visible in the image, verbatim, empty if there is none,
+hashtags - up to five hashtags describing the image, without '#',
+content_warnings - short names of topics viewers may want to be warned about (e.g. gore, spiders, flashing lights), empty if there are none.";
+
+// strict structured output requires all fields to be listed as required and no other fields
+fn description_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "alt_text": { "type": "string" },
+            "long_description": { "type": "string" },
+            "detected_text": { "type": "string" },
+            "hashtags": { "type": "array", "items": { "type": "string" } },
+            "content_warnings": { "type": "array", "items": { "type": "string" } },
+        },
+        "required": ["alt_text", "long_description", "detected_text", "hashtags", "content_warnings"],
+        "additionalProperties": false,
+    })
+}